@@ -37,3 +37,62 @@ const setValueForMs = (element, text, ms) => {
         }
     }, ms);
 }
+
+const pushForm = document.getElementById("push-form");
+const pushButton = document.getElementById("push");
+
+const SUBSCRIBE_TEXT = pushButton?.value;
+const UNSUBSCRIBE_TEXT = 'Stop notifications';
+
+if (pushForm && 'serviceWorker' in navigator && 'PushManager' in window) {
+    pushForm.hidden = false;
+    // which locations this browser is subscribed to, the server has no other way to say
+    const pushKey = `push:${pushForm.dataset.query}`;
+    if (localStorage.getItem(pushKey)) {
+        pushButton.value = UNSUBSCRIBE_TEXT;
+    }
+
+    const params = new URLSearchParams(pushForm.dataset.query);
+    const location = {
+        lat: Number(params.get('lat')),
+        lon: Number(params.get('lon')),
+        interpolation: params.get('interpolation') ?? undefined,
+    };
+
+    pushButton.addEventListener('click', async (event) => {
+        try {
+            const registration = await navigator.serviceWorker.register('/sw.js');
+            let subscription = await registration.pushManager.getSubscription();
+
+            if (localStorage.getItem(pushKey)) {
+                if (subscription) {
+                    await fetch('/api/push', {
+                        method: 'DELETE',
+                        headers: { 'Content-Type': 'application/json' },
+                        body: JSON.stringify({ endpoint: subscription.endpoint, ...location }),
+                    });
+                }
+                localStorage.removeItem(pushKey);
+                event.target.value = SUBSCRIBE_TEXT;
+                return;
+            }
+
+            subscription ??= await registration.pushManager.subscribe({
+                userVisibleOnly: true,
+                applicationServerKey: pushForm.dataset.key,
+            });
+            const response = await fetch('/api/push', {
+                method: 'POST',
+                headers: { 'Content-Type': 'application/json' },
+                body: JSON.stringify({ subscription: subscription.toJSON(), ...location }),
+            });
+            if (!response.ok) {
+                throw new Error(`subscribing failed with ${response.status}`);
+            }
+            localStorage.setItem(pushKey, '1');
+            event.target.value = UNSUBSCRIBE_TEXT;
+        } catch {
+            setValueForMs(event.target, 'Failed to set up notifications', 5000);
+        }
+    });
+}
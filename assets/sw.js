@@ -0,0 +1,13 @@
+self.addEventListener('push', (event) => {
+    const data = event.data?.json() ?? {};
+    event.waitUntil(self.registration.showNotification(data.title ?? 'pollen.party', {
+        body: data.body,
+        icon: '/favicon.ico',
+        data: { url: data.url ?? '/' },
+    }));
+});
+
+self.addEventListener('notificationclick', (event) => {
+    event.notification.close();
+    event.waitUntil(clients.openWindow(event.notification.data.url));
+});
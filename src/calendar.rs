@@ -0,0 +1,119 @@
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+
+use crate::{
+    daily::periods,
+    silam::{Pollen, PollenIndex},
+};
+
+/// iCalendar with an event for each period at or above `threshold`.
+///
+/// UIDs are made from the location, threshold and the hour the period starts, so a refreshed
+/// forecast still starting a period then updates its event in subscribed calendars rather than
+/// adding another, and periods coming or going earlier in the day don't change the rest.
+pub fn high_pollen_calendar(
+    pollen: &[Pollen],
+    threshold: PollenIndex,
+    location: &str,
+    (lon, lat): (f32, f32),
+    tz: &Tz,
+    fetch_time: DateTime<Utc>,
+) -> String {
+    let mut ics = String::new();
+    push_line(&mut ics, "BEGIN:VCALENDAR");
+    push_line(&mut ics, "VERSION:2.0");
+    push_line(&mut ics, "PRODID:-//pollen.party//Pollen forecast//EN");
+    push_line(&mut ics, "CALSCALE:GREGORIAN");
+    push_line(&mut ics, "METHOD:PUBLISH");
+    push_line(
+        &mut ics,
+        &format!(
+            "X-WR-CALNAME:{}",
+            escape_text(&format!("Pollen at {}", location))
+        ),
+    );
+    push_line(&mut ics, &format!("X-WR-TIMEZONE:{}", tz.name()));
+
+    for period in periods(pollen, |pollen| pollen.pollen_index >= threshold) {
+        let (start, end) = (period.start(), period.end());
+
+        let title = format!(
+            "{} {} pollen",
+            capitalise(period.max_index.to_spoken()),
+            period.source.to_spoken()
+        );
+        let description = format!(
+            "Pollen at or above {} from {} until {} ({}), highest {}. Data from FMI SILAM and EAN.",
+            threshold.to_spoken(),
+            start.with_timezone(tz).format("%a %e %b %H:%M"),
+            end.with_timezone(tz).format("%a %e %b %H:%M"),
+            tz.name(),
+            period.max_index.to_spoken(),
+        );
+
+        push_line(&mut ics, "BEGIN:VEVENT");
+        push_line(
+            &mut ics,
+            &format!(
+                "UID:{}-{:.2}-{:.2}-{}@pollen.party",
+                start.format("%Y%m%dT%H"),
+                lat,
+                lon,
+                threshold
+            ),
+        );
+        push_line(&mut ics, &format!("DTSTAMP:{}", ics_time(&fetch_time)));
+        push_line(
+            &mut ics,
+            &format!("LAST-MODIFIED:{}", ics_time(&fetch_time)),
+        );
+        push_line(&mut ics, &format!("DTSTART:{}", ics_time(&start)));
+        push_line(&mut ics, &format!("DTEND:{}", ics_time(&end)));
+        push_line(&mut ics, &format!("SUMMARY:{}", escape_text(&title)));
+        push_line(
+            &mut ics,
+            &format!("DESCRIPTION:{}", escape_text(&description)),
+        );
+        push_line(&mut ics, &format!("LOCATION:{}", escape_text(location)));
+        push_line(&mut ics, &format!("GEO:{:.2};{:.2}", lat, lon));
+        push_line(&mut ics, "TRANSP:TRANSPARENT");
+        push_line(&mut ics, "END:VEVENT");
+    }
+
+    push_line(&mut ics, "END:VCALENDAR");
+    ics
+}
+
+fn ics_time(time: &DateTime<Utc>) -> String {
+    time.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+pub fn capitalise(text: &str) -> String {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Escapes a TEXT value as RFC 5545 requires.
+fn escape_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// Appends a content line, folded so that no line is longer than 75 octets.
+fn push_line(ics: &mut String, line: &str) {
+    let mut line_length = 0;
+    for c in line.chars() {
+        if line_length + c.len_utf8() > 75 {
+            ics.push_str("\r\n ");
+            line_length = 1;
+        }
+        ics.push(c);
+        line_length += c.len_utf8();
+    }
+    ics.push_str("\r\n");
+}
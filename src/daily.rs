@@ -0,0 +1,190 @@
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use chrono_tz::Tz;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::silam::{Pollen, PollenIndex, PollenType};
+
+/// The hours of one calendar day in the location's time zone.
+#[derive(Serialize)]
+pub struct DaySummary {
+    pub date: NaiveDate,
+    /// Hours summarised: 24, or 23 and 25 on days the clocks change, and fewer on days only
+    /// partly inside the window.
+    pub hours: usize,
+    pub max_index: PollenIndex,
+    /// First hour at `max_index`.
+    pub peak_time: DateTime<Utc>,
+    pub peak_source: PollenType,
+    /// Sources of the hours at `max_index`, most hours first.
+    pub dominant_sources: Vec<PollenType>,
+    pub hours_at_level: BTreeMap<PollenIndex, usize>,
+}
+
+/// A run of consecutive hours that all meet some condition.
+pub struct Period<'a> {
+    pub hours: &'a [Pollen],
+    pub max_index: PollenIndex,
+    /// Source of the most hours in the period.
+    pub source: PollenType,
+}
+
+impl Period<'_> {
+    pub fn start(&self) -> DateTime<Utc> {
+        self.hours[0].time
+    }
+
+    /// End of the last hour.
+    pub fn end(&self) -> DateTime<Utc> {
+        self.hours[self.hours.len() - 1].time + Duration::hours(1)
+    }
+}
+
+/// Every run of consecutive hours for which `include` holds, in order.
+pub fn periods(pollen: &[Pollen], include: impl Fn(&Pollen) -> bool) -> Vec<Period<'_>> {
+    pollen
+        .chunk_by(|a, b| include(a) == include(b))
+        .filter(|hours| hours.first().is_some_and(&include))
+        .filter_map(|hours| {
+            Some(Period {
+                hours,
+                max_index: hours.iter().map(|pollen| pollen.pollen_index).max()?,
+                source: *dominant_sources(hours.iter()).first()?,
+            })
+        })
+        .collect()
+}
+
+/// Groups hourly values by their date in `tz`, in order.
+pub fn summarise_days(pollen: &[Pollen], tz: &Tz) -> Vec<DaySummary> {
+    pollen
+        .chunk_by(|a, b| local_date(a, tz) == local_date(b, tz))
+        .filter_map(|day| summarise_day(day, tz))
+        .collect()
+}
+
+fn summarise_day(day: &[Pollen], tz: &Tz) -> Option<DaySummary> {
+    let first = day.first()?;
+    let max_index = day.iter().map(|pollen| pollen.pollen_index).max()?;
+    let peak = day.iter().find(|pollen| pollen.pollen_index == max_index)?;
+
+    let mut hours_at_level: BTreeMap<PollenIndex, usize> = PollenIndex::LEVELS
+        .iter()
+        .map(|level| (*level, 0))
+        .collect();
+    for pollen in day {
+        *hours_at_level.entry(pollen.pollen_index).or_default() += 1;
+    }
+
+    Some(DaySummary {
+        date: local_date(first, tz),
+        hours: day.len(),
+        max_index,
+        peak_time: peak.time,
+        peak_source: peak.pollen_index_source,
+        dominant_sources: dominant_sources(
+            day.iter().filter(|pollen| pollen.pollen_index == max_index),
+        ),
+        hours_at_level,
+    })
+}
+
+/// Sources of the given hours, most hours first.
+pub fn dominant_sources<'a>(hours: impl Iterator<Item = &'a Pollen>) -> Vec<PollenType> {
+    let mut source_hours: Vec<(PollenType, usize)> = Vec::new();
+    for pollen in hours {
+        match source_hours
+            .iter_mut()
+            .find(|(source, _)| *source == pollen.pollen_index_source)
+        {
+            Some((_, hours)) => *hours += 1,
+            None => source_hours.push((pollen.pollen_index_source, 1)),
+        }
+    }
+    // stable, so sources with equal hours stay in order of first appearance
+    source_hours.sort_by(|(_, a), (_, b)| b.cmp(a));
+    source_hours.into_iter().map(|(source, _)| source).collect()
+}
+
+fn local_date(pollen: &Pollen, tz: &Tz) -> NaiveDate {
+    pollen.time.with_timezone(tz).date_naive()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{silam::Concentrations, window::ForecastWindow};
+    use chrono::Timelike;
+    use chrono_tz::Europe::London;
+
+    /// Hourly values over `days` whole London days from `date`, with the index rising each hour
+    /// of a day and wrapping at the top level.
+    fn hours(date: &str, days: u64) -> Vec<Pollen> {
+        let window = ForecastWindow::whole_days(date.parse().unwrap(), days, &London).unwrap();
+        (0..window.hours)
+            .map(|hour| {
+                let time = window.start + Duration::hours(hour as i64);
+                let local_hour = time.with_timezone(&London).hour() as usize;
+                Pollen {
+                    time,
+                    pollen_index: PollenIndex::LEVELS[local_hour % PollenIndex::LEVELS.len()],
+                    pollen_index_source: PollenType::Grass,
+                    concentrations: Concentrations::default(),
+                }
+            })
+            .collect()
+    }
+
+    fn utc(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().to_utc()
+    }
+
+    fn dates_and_hours(days: &[DaySummary]) -> Vec<(String, usize)> {
+        days.iter()
+            .map(|day| (day.date.to_string(), day.hours))
+            .collect()
+    }
+
+    #[test]
+    fn buckets_hours_across_spring_forward() {
+        let pollen = hours("2024-03-30", 3);
+        let days = summarise_days(&pollen, &London);
+        assert_eq!(
+            dates_and_hours(&days),
+            [
+                ("2024-03-30".to_string(), 24),
+                ("2024-03-31".to_string(), 23),
+                ("2024-04-01".to_string(), 24)
+            ]
+        );
+        // the day after the change starts an hour earlier in UTC
+        assert_eq!(pollen[24].time, utc("2024-03-31T00:00:00Z"));
+        assert_eq!(pollen[24 + 23].time, utc("2024-03-31T23:00:00Z"));
+        // 01:00 local never happens, so one level gets an hour fewer
+        assert_eq!(days[1].hours_at_level.values().sum::<usize>(), 23);
+        assert_eq!(days[1].hours_at_level[&PollenIndex::VeryLow], 3);
+        assert_eq!(days[0].hours_at_level[&PollenIndex::VeryLow], 4);
+    }
+
+    #[test]
+    fn buckets_hours_across_fall_back() {
+        let pollen = hours("2024-10-26", 3);
+        let days = summarise_days(&pollen, &London);
+        assert_eq!(
+            dates_and_hours(&days),
+            [
+                ("2024-10-26".to_string(), 24),
+                ("2024-10-27".to_string(), 25),
+                ("2024-10-28".to_string(), 24)
+            ]
+        );
+        assert_eq!(pollen[24].time, utc("2024-10-26T23:00:00Z"));
+        assert_eq!(pollen[24 + 25].time, utc("2024-10-28T00:00:00Z"));
+        // 01:00 local happens twice, so one level gets an extra hour
+        assert_eq!(days[1].hours_at_level[&PollenIndex::VeryLow], 5);
+        assert_eq!(days[0].hours_at_level[&PollenIndex::VeryLow], 4);
+        // the peak is the first hour at the top level, 05:00 GMT
+        assert_eq!(days[1].max_index, PollenIndex::VeryHigh);
+        assert_eq!(days[1].peak_time, utc("2024-10-27T05:00:00Z"));
+    }
+}
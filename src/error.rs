@@ -0,0 +1,236 @@
+use axum::{
+    extract::rejection::{FormRejection, JsonRejection, QueryRejection},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use reqwest::StatusCode;
+use serde::Serialize;
+use std::fmt::Display;
+
+use crate::{
+    html::{error_page, page},
+    silam::Silam,
+};
+
+/// Everything that can go wrong while answering a request. Rendered as a JSON `ApiError` by
+/// default, or as a page of the site through `into_html`.
+#[derive(Debug)]
+pub enum AppError {
+    /// The query string could not be parsed.
+    InvalidQuery(String),
+    /// The request body could not be parsed.
+    InvalidBody(String),
+    /// A batch asked for more locations than are accepted.
+    BatchTooLarge(usize),
+    /// `lat` and `lon` were not both given.
+    MissingCoordinates,
+    /// Coordinates are not a point on the globe.
+    InvalidCoordinates,
+    /// Coordinates have more decimal places than are accepted.
+    TooManyDecimalPlaces(usize),
+    /// The point is outside the area SILAM models, or has no data there.
+    OutsideCoverage,
+    /// The loaded SILAM data does not cover the requested times.
+    DataUnavailable,
+    /// The requested forecast window could not be understood.
+    InvalidWindow(String),
+    /// The requested forecast window reaches outside the loaded SILAM data.
+    WindowOutOfRange {
+        available_start: DateTime<Utc>,
+        available_end: DateTime<Utc>,
+    },
+    /// No usable time zone is known for the location.
+    UnknownTimezone(String),
+    /// The geocoder returned something that could not be used.
+    Geocoding(String),
+    /// Too many location searches are waiting for the geocoder.
+    SearchBusy,
+    /// No subscription has the requested id.
+    SubscriptionNotFound,
+    /// The limit on subscriptions, in all or for one target, has been reached.
+    TooManySubscriptions,
+    /// Something on the server failed, such as saving to disk.
+    Internal(String),
+    /// No SMTP relay is configured.
+    EmailUnavailable,
+    /// The SMTP relay refused or failed to take an email.
+    Email(String),
+    /// A confirmation or unsubscribe link was not signed by this server.
+    InvalidToken,
+    /// A request lacked the bearer token it needs, such as the admin token or a webhook's
+    /// secret.
+    Unauthorized,
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::InvalidQuery(_)
+            | AppError::InvalidBody(_)
+            | AppError::MissingCoordinates
+            | AppError::InvalidCoordinates
+            | AppError::TooManyDecimalPlaces(_)
+            | AppError::InvalidWindow(_) => StatusCode::BAD_REQUEST,
+            AppError::BatchTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::OutsideCoverage | AppError::WindowOutOfRange { .. } => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            AppError::DataUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::UnknownTimezone(_) | AppError::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            AppError::Geocoding(_) => StatusCode::BAD_GATEWAY,
+            AppError::SearchBusy => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::SubscriptionNotFound => StatusCode::NOT_FOUND,
+            AppError::TooManySubscriptions => StatusCode::TOO_MANY_REQUESTS,
+            AppError::EmailUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Email(_) => StatusCode::BAD_GATEWAY,
+            AppError::InvalidToken => StatusCode::FORBIDDEN,
+            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    /// Stable identifier for API clients to match on, unlike the message.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::InvalidQuery(_) => "invalid_query",
+            AppError::InvalidBody(_) => "invalid_body",
+            AppError::BatchTooLarge(_) => "batch_too_large",
+            AppError::MissingCoordinates => "missing_coordinates",
+            AppError::InvalidCoordinates => "invalid_coordinates",
+            AppError::TooManyDecimalPlaces(_) => "too_many_decimal_places",
+            AppError::OutsideCoverage => "outside_coverage",
+            AppError::DataUnavailable => "data_unavailable",
+            AppError::InvalidWindow(_) => "invalid_window",
+            AppError::WindowOutOfRange { .. } => "window_out_of_range",
+            AppError::UnknownTimezone(_) => "unknown_timezone",
+            AppError::Geocoding(_) => "geocoding_failed",
+            AppError::SearchBusy => "search_busy",
+            AppError::SubscriptionNotFound => "subscription_not_found",
+            AppError::TooManySubscriptions => "too_many_subscriptions",
+            AppError::Internal(_) => "internal_error",
+            AppError::EmailUnavailable => "email_unavailable",
+            AppError::Email(_) => "email_failed",
+            AppError::InvalidToken => "invalid_token",
+            AppError::Unauthorized => "unauthorized",
+        }
+    }
+
+    pub fn to_api_error(&self) -> ApiError {
+        ApiError {
+            code: self.code(),
+            msg: self.to_string(),
+        }
+    }
+
+    pub fn into_html(self, silam: &Silam) -> HtmlError {
+        HtmlError {
+            error: self,
+            fetch_time: silam.fetch_time,
+            run_time: silam.run_time,
+        }
+    }
+}
+
+impl Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppError::InvalidQuery(reason) => write!(f, "Invalid query: {}", reason),
+            AppError::InvalidBody(reason) => write!(f, "Invalid request body: {}", reason),
+            AppError::BatchTooLarge(max) => {
+                write!(f, "Batches accept maximum {} locations", max)
+            }
+            AppError::MissingCoordinates => write!(f, "?lat=&lon= query params missing"),
+            AppError::InvalidCoordinates => write!(
+                f,
+                "Coordinates must be a latitude between -90 and 90 and a longitude between -180 and 180"
+            ),
+            AppError::TooManyDecimalPlaces(places) => {
+                write!(f, "Coordinates accept maximum {} decimal places", places)
+            }
+            AppError::OutsideCoverage => write!(f, "Location is outside the area covered by SILAM"),
+            AppError::DataUnavailable => {
+                write!(f, "Forecast data for the requested times is not available")
+            }
+            AppError::InvalidWindow(reason) => write!(f, "Invalid forecast window: {}", reason),
+            AppError::WindowOutOfRange {
+                available_start,
+                available_end,
+            } => write!(
+                f,
+                "Forecast window is outside the available data, which runs from {} until {}",
+                available_start.to_rfc3339_opts(SecondsFormat::Secs, true),
+                available_end.to_rfc3339_opts(SecondsFormat::Secs, true),
+            ),
+            AppError::UnknownTimezone(name) => {
+                write!(f, "Time zone of the location is not known: {:?}", name)
+            }
+            AppError::Geocoding(reason) => write!(f, "Location search failed: {}", reason),
+            AppError::SearchBusy => write!(
+                f,
+                "Location search is busy right now, please try again in a moment"
+            ),
+            AppError::SubscriptionNotFound => write!(f, "Subscription not found"),
+            AppError::TooManySubscriptions => {
+                write!(f, "Too many subscriptions, remove some before adding more")
+            }
+            AppError::Internal(reason) => write!(f, "Internal error: {}", reason),
+            AppError::EmailUnavailable => write!(f, "Email is not available on this server"),
+            AppError::Email(reason) => write!(f, "Failed to send email: {}", reason),
+            AppError::InvalidToken => write!(f, "This link is not valid"),
+            AppError::Unauthorized => write!(f, "Missing or wrong bearer token"),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl From<QueryRejection> for AppError {
+    fn from(rejection: QueryRejection) -> AppError {
+        AppError::InvalidQuery(rejection.body_text())
+    }
+}
+
+impl From<FormRejection> for AppError {
+    fn from(rejection: FormRejection) -> AppError {
+        AppError::InvalidBody(rejection.body_text())
+    }
+}
+
+impl From<JsonRejection> for AppError {
+    fn from(rejection: JsonRejection) -> AppError {
+        AppError::InvalidBody(rejection.body_text())
+    }
+}
+
+#[derive(Serialize)]
+pub struct ApiError {
+    code: &'static str,
+    msg: String,
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        (self.status(), Json(self.to_api_error())).into_response()
+    }
+}
+
+/// An `AppError` rendered as a page, with the footer of the data that was being served.
+pub struct HtmlError {
+    error: AppError,
+    fetch_time: DateTime<Utc>,
+    run_time: DateTime<Utc>,
+}
+
+impl IntoResponse for HtmlError {
+    fn into_response(self) -> Response {
+        let body = page(
+            true,
+            self.fetch_time,
+            self.run_time,
+            error_page(&self.error.to_string()),
+        );
+        (self.error.status(), body).into_response()
+    }
+}
@@ -0,0 +1,64 @@
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+
+/// The dataset a feed entry describes and what it says about the location.
+pub struct Outlook<'a> {
+    pub location: &'a str,
+    pub date: NaiveDate,
+    pub text: &'a str,
+    pub fetch_time: DateTime<Utc>,
+    pub run_time: DateTime<Utc>,
+}
+
+/// Atom feed for a location holding one entry for the loaded dataset. Entry ids come from the
+/// model run, so feed readers show each new dataset as a new entry and keep the old ones.
+pub fn outlook_feed(site_url: &str, (lon, lat): (f32, f32), outlook: &Outlook) -> String {
+    let query = format!("lat={}&lon={}", lat, lon);
+    let feed_url = format!("{}/feed.atom?{}", site_url, query);
+    let page_url = format!("{}/?{}", site_url, query);
+    let updated = atom_time(&outlook.fetch_time);
+    let entry_id = format!("{}#{}", feed_url, atom_time(&outlook.run_time));
+
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>{title}</title>
+  <id>{feed_id}</id>
+  <link rel="self" href="{feed_href}"/>
+  <link rel="alternate" type="text/html" href="{page_href}"/>
+  <updated>{updated}</updated>
+  <author><name>pollen.party</name></author>
+  <rights>Data from FMI SILAM and EAN</rights>
+  <entry>
+    <title>{entry_title}</title>
+    <id>{entry_id}</id>
+    <link rel="alternate" type="text/html" href="{page_href}"/>
+    <published>{updated}</published>
+    <updated>{updated}</updated>
+    <summary type="text">{summary}</summary>
+  </entry>
+</feed>
+"#,
+        title = escape_xml(&format!("pollen.party: {}", outlook.location)),
+        feed_id = escape_xml(&feed_url),
+        feed_href = escape_xml(&feed_url),
+        page_href = escape_xml(&page_url),
+        updated = updated,
+        entry_title = escape_xml(&format!(
+            "Pollen outlook for {}",
+            outlook.date.format("%A %-d %B")
+        )),
+        entry_id = escape_xml(&entry_id),
+        summary = escape_xml(outlook.text),
+    )
+}
+
+fn atom_time(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
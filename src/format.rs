@@ -0,0 +1,239 @@
+use axum::http::{header, HeaderMap};
+use chrono::{DateTime, SecondsFormat, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+
+use crate::silam::{Pollen, PollenType};
+
+/// How `/api` renders its response, from the `format` param or else the `Accept` header.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Json,
+    /// One row per hour with `#` comment lines holding the attribution and location.
+    Csv,
+    /// One JSON object per hour, each carrying the attribution.
+    Ndjson,
+}
+
+impl Format {
+    pub fn from_accept(headers: &HeaderMap) -> Format {
+        let accept = headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .unwrap_or_default();
+        // first listed type we know wins, quality values are not worth weighing here
+        accept
+            .split(',')
+            .map(|media_type| media_type.split(';').next().unwrap_or_default().trim())
+            .find_map(|media_type| match media_type {
+                "application/json" => Some(Format::Json),
+                "text/csv" => Some(Format::Csv),
+                "application/x-ndjson" | "application/ndjson" => Some(Format::Ndjson),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Ndjson => "application/x-ndjson",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ApiResponse {
+    pub attribution: String,
+    pub fetch_time: DateTime<Utc>,
+    pub run_time: DateTime<Utc>,
+    pub location: String,
+    pub pollen: Vec<Pollen>,
+}
+
+/// A flattened `Pollen` for row-based formats.
+#[derive(Serialize)]
+struct PollenRow<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attribution: Option<&'a str>,
+    time_utc: String,
+    time_local: String,
+    pollen_index: u8,
+    pollen_index_source: &'a str,
+    alder: f32,
+    birch: f32,
+    grass: f32,
+    olive: f32,
+    mugwort: f32,
+    ragweed: f32,
+}
+
+impl<'a> PollenRow<'a> {
+    fn new(pollen: &'a Pollen, tz: &Tz, attribution: Option<&'a str>) -> PollenRow<'a> {
+        let concentrations = &pollen.concentrations;
+        PollenRow {
+            attribution,
+            time_utc: pollen.time.to_rfc3339_opts(SecondsFormat::Secs, true),
+            time_local: pollen
+                .time
+                .with_timezone(tz)
+                .to_rfc3339_opts(SecondsFormat::Secs, false),
+            pollen_index: pollen.pollen_index as u8,
+            pollen_index_source: pollen.pollen_index_source.to_spoken(),
+            alder: concentrations.get(PollenType::Alder),
+            birch: concentrations.get(PollenType::Birch),
+            grass: concentrations.get(PollenType::Grass),
+            olive: concentrations.get(PollenType::Olive),
+            mugwort: concentrations.get(PollenType::Mugwort),
+            ragweed: concentrations.get(PollenType::Ragweed),
+        }
+    }
+}
+
+impl ApiResponse {
+    pub fn to_csv(&self, tz: &Tz) -> String {
+        let comments = [
+            self.attribution.clone(),
+            format!("Location: {}", self.location),
+            format!("Fetched at: {}", self.fetch_time.to_rfc3339()),
+            format!("Model run at: {}", self.run_time.to_rfc3339()),
+        ];
+        let rows = self
+            .pollen
+            .iter()
+            .map(|pollen| PollenRow::new(pollen, tz, None));
+        write_csv(&comments, rows)
+    }
+
+    pub fn to_ndjson(&self, tz: &Tz) -> String {
+        let mut ndjson = String::new();
+        for pollen in &self.pollen {
+            let row = PollenRow::new(pollen, tz, Some(&self.attribution));
+            ndjson.push_str(&serde_json::to_string(&row).expect("pollen rows always serialize"));
+            ndjson.push('\n');
+        }
+        ndjson
+    }
+}
+
+/// CSV with a header row from the fields of `T`, after `#` comment lines. Fields are quoted as
+/// needed, and comments kept to one line each.
+fn write_csv<T: Serialize>(comments: &[String], rows: impl IntoIterator<Item = T>) -> String {
+    let mut csv = String::new();
+    for comment in comments {
+        // writing to a String cannot fail
+        let _ = writeln!(csv, "# {}", comment.replace(['\r', '\n'], " "));
+    }
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for row in rows {
+        writer
+            .serialize(row)
+            .expect("rows of plain fields always serialize");
+    }
+    let body = writer.into_inner().expect("writing to a Vec cannot fail");
+    csv.push_str(&String::from_utf8(body).expect("serialized strings are UTF-8"));
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::silam::{Concentrations, PollenIndex};
+
+    fn read_csv(csv: &str) -> Vec<csv::StringRecord> {
+        csv::ReaderBuilder::new()
+            .comment(Some(b'#'))
+            .has_headers(false)
+            .from_reader(csv.as_bytes())
+            .records()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn round_trips_quoted_fields() {
+        #[derive(Serialize)]
+        struct Row {
+            name: &'static str,
+            value: u8,
+        }
+        let csv = write_csv(
+            &["Comment\nacross lines".to_string()],
+            [
+                Row {
+                    name: "Paris, \"Texas\"\nUS",
+                    value: 1,
+                },
+                Row {
+                    name: "plain",
+                    value: 2,
+                },
+            ],
+        );
+        assert!(csv.starts_with("# Comment across lines\n"));
+        let records = read_csv(&csv);
+        assert_eq!(records.len(), 3);
+        assert_eq!(&records[0], vec!["name", "value"]);
+        assert_eq!(&records[1], vec!["Paris, \"Texas\"\nUS", "1"]);
+        assert_eq!(&records[2], vec!["plain", "2"]);
+    }
+
+    #[test]
+    fn writes_api_csv() {
+        let time = DateTime::parse_from_rfc3339("2024-03-31T00:00:00Z")
+            .unwrap()
+            .to_utc();
+        let response = ApiResponse {
+            attribution: "Data from FMI SILAM and EAN".to_string(),
+            fetch_time: time,
+            run_time: time,
+            location: "Helsinki,\nUusimaa".to_string(),
+            pollen: vec![Pollen {
+                time,
+                pollen_index: PollenIndex::Low,
+                pollen_index_source: PollenType::Birch,
+                concentrations: Concentrations {
+                    birch: 12.5,
+                    ..Concentrations::default()
+                },
+            }],
+        };
+        let csv = response.to_csv(&chrono_tz::Europe::Helsinki);
+        assert!(csv.contains("# Location: Helsinki, Uusimaa\n"));
+        let records = read_csv(&csv);
+        assert_eq!(
+            &records[0],
+            vec![
+                "time_utc",
+                "time_local",
+                "pollen_index",
+                "pollen_index_source",
+                "alder",
+                "birch",
+                "grass",
+                "olive",
+                "mugwort",
+                "ragweed"
+            ]
+        );
+        assert_eq!(
+            &records[1],
+            vec![
+                "2024-03-31T00:00:00Z",
+                "2024-03-31T02:00:00+02:00",
+                "2",
+                "birch",
+                "0.0",
+                "12.5",
+                "0.0",
+                "0.0",
+                "0.0",
+                "0.0"
+            ]
+        );
+    }
+}
@@ -0,0 +1,482 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    error::Error,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::task;
+
+use crate::{error::AppError, geocode::GeocodedPlace, metrics::METRICS, source::write_atomic};
+
+/// Searches kept in memory, the least recently used are dropped past this.
+const MEMORY_CAPACITY: usize = 1000;
+/// Searches kept on disk, the oldest are dropped past this when the log is compacted.
+const DISK_CAPACITY: usize = 10_000;
+/// Lines the log can grow to before it is compacted.
+const MAX_LOG_LINES: usize = 2 * DISK_CAPACITY;
+/// How long a search that found nothing is remembered, shorter than for places so new or
+/// corrected entries in OSM show up.
+const NEGATIVE_TTL: Duration = Duration::days(1);
+
+#[derive(Serialize, Deserialize, Clone)]
+struct CachedSearch {
+    /// Normalised query.
+    query: String,
+    /// Empty if no provider found anything.
+    places: Vec<GeocodedPlace>,
+    cached: DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct Recent {
+    searches: HashMap<String, (CachedSearch, u64)>,
+    /// Incremented on each use, the search with the lowest is the least recently used.
+    clock: u64,
+}
+
+/// Searches saved in the data directory as JSON lines, appended as they are cached so saving
+/// one doesn't rewrite the rest. Only where each search's line starts is kept in memory.
+struct Log {
+    path: PathBuf,
+    file: File,
+    index: HashMap<String, Saved>,
+    /// Lines in the file, including those of searches since cached again or purged.
+    lines: usize,
+}
+
+/// Where the latest line for a search starts.
+struct Saved {
+    offset: u64,
+    cached: DateTime<Utc>,
+    found: bool,
+}
+
+/// Results of location searches by normalised query, so repeat searches don't go to the
+/// geocoders again. The most recently used are kept in memory, and all of them in the data
+/// directory, where they survive restarts.
+pub struct GeocodeCache {
+    recent: Mutex<Recent>,
+    log: Option<Arc<Mutex<Log>>>,
+    ttl: Duration,
+}
+
+impl GeocodeCache {
+    pub fn open(data_dir: Option<&Path>, ttl: Duration) -> Result<GeocodeCache, Box<dyn Error>> {
+        let log = match data_dir {
+            Some(dir) => Some(Arc::new(Mutex::new(Log::open(
+                dir.join("geocoding.jsonl"),
+                ttl,
+            )?))),
+            None => None,
+        };
+        Ok(GeocodeCache {
+            recent: Mutex::new(Recent::default()),
+            log,
+            ttl,
+        })
+    }
+
+    /// The cached result of `query`, empty if it is known to find nothing.
+    pub async fn get(&self, query: &str) -> Option<Vec<GeocodedPlace>> {
+        let query = normalise(query);
+        let now = Utc::now();
+        let mut cached = self.get_recent(&query, now);
+        if let (None, Some(log)) = (&cached, &self.log) {
+            let log = Arc::clone(log);
+            let ttl = self.ttl;
+            cached = task::spawn_blocking(move || {
+                log.lock()
+                    .unwrap()
+                    .get(&query, now, ttl)
+                    .unwrap_or_else(|err| {
+                        println!("Failed to read cached location search: {}", err);
+                        None
+                    })
+            })
+            .await
+            .ok()
+            .flatten();
+            if let Some(search) = &cached {
+                self.remember(search.clone());
+            }
+        }
+        METRICS.record_cache("geocoding", cached.is_some());
+        cached.map(|search| search.places)
+    }
+
+    /// Caches the result of `query`, saving it in the background.
+    pub fn insert(&self, query: &str, places: Vec<GeocodedPlace>) {
+        let search = CachedSearch {
+            query: normalise(query),
+            places,
+            cached: Utc::now(),
+        };
+        self.remember(search.clone());
+        if let Some(log) = &self.log {
+            let log = Arc::clone(log);
+            let ttl = self.ttl;
+            task::spawn_blocking(move || {
+                // the search still worked, it will just be repeated after a restart
+                if let Err(err) = log.lock().unwrap().append(&search, ttl) {
+                    println!("Failed to cache location search: {}", err);
+                }
+            });
+        }
+    }
+
+    /// Forgets `query`, or every search if None, returning how many were forgotten.
+    pub async fn purge(&self, query: Option<&str>) -> Result<usize, AppError> {
+        let query = query.map(normalise);
+        let mut purged = HashSet::new();
+        {
+            let mut recent = self.recent.lock().unwrap();
+            match &query {
+                Some(query) => {
+                    purged.extend(recent.searches.remove_entry(query).map(|(query, _)| query))
+                }
+                None => purged.extend(recent.searches.drain().map(|(query, _)| query)),
+            }
+        }
+        if let Some(log) = &self.log {
+            let log = Arc::clone(log);
+            let ttl = self.ttl;
+            let saved = task::spawn_blocking(move || {
+                log.lock()
+                    .unwrap()
+                    .purge(query.as_deref(), ttl)
+                    .map_err(|err| err.to_string())
+            })
+            .await
+            .map_err(|err| AppError::Internal(err.to_string()))?
+            .map_err(|err| {
+                AppError::Internal(format!("failed to purge geocoding cache: {}", err))
+            })?;
+            purged.extend(saved);
+        }
+        Ok(purged.len())
+    }
+
+    fn get_recent(&self, query: &str, now: DateTime<Utc>) -> Option<CachedSearch> {
+        let mut recent = self.recent.lock().unwrap();
+        recent.clock += 1;
+        let clock = recent.clock;
+        match recent.searches.get_mut(query) {
+            Some((search, last_used)) if self.is_fresh(search, now) => {
+                *last_used = clock;
+                Some(search.clone())
+            }
+            Some(_) => {
+                recent.searches.remove(query);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn remember(&self, search: CachedSearch) {
+        let mut recent = self.recent.lock().unwrap();
+        recent.clock += 1;
+        let clock = recent.clock;
+        if recent.searches.len() >= MEMORY_CAPACITY && !recent.searches.contains_key(&search.query)
+        {
+            let least_recent = recent
+                .searches
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(query, _)| query.clone());
+            if let Some(query) = least_recent {
+                recent.searches.remove(&query);
+            }
+        }
+        recent
+            .searches
+            .insert(search.query.clone(), (search, clock));
+    }
+
+    fn is_fresh(&self, search: &CachedSearch, now: DateTime<Utc>) -> bool {
+        is_fresh(search.cached, !search.places.is_empty(), self.ttl, now)
+    }
+}
+
+impl Log {
+    /// Reads where each search in `path` is, then compacts it, which also drops a line cut
+    /// short by a crash.
+    fn open(path: PathBuf, ttl: Duration) -> Result<Log, Box<dyn Error>> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let mut index = HashMap::new();
+        let mut reader = BufReader::new(&file);
+        let mut offset = 0;
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let read = reader.read_until(b'\n', &mut line)?;
+            if read == 0 {
+                break;
+            }
+            if let Ok(search) = serde_json::from_slice::<CachedSearch>(&line) {
+                index.insert(
+                    search.query,
+                    Saved {
+                        offset,
+                        cached: search.cached,
+                        found: !search.places.is_empty(),
+                    },
+                );
+            }
+            offset += read as u64;
+        }
+        let mut log = Log {
+            path,
+            file,
+            index,
+            lines: 0,
+        };
+        log.compact(Utc::now(), ttl)?;
+        Ok(log)
+    }
+
+    fn get(
+        &self,
+        query: &str,
+        now: DateTime<Utc>,
+        ttl: Duration,
+    ) -> Result<Option<CachedSearch>, Box<dyn Error>> {
+        match self.index.get(query) {
+            Some(saved) if is_fresh(saved.cached, saved.found, ttl, now) => Ok(Some(
+                serde_json::from_slice(&self.read_line(saved.offset)?)?,
+            )),
+            _ => Ok(None),
+        }
+    }
+
+    fn append(&mut self, search: &CachedSearch, ttl: Duration) -> Result<(), Box<dyn Error>> {
+        let mut line = serde_json::to_vec(search)?;
+        line.push(b'\n');
+        let offset = self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(&line)?;
+        self.index.insert(
+            search.query.clone(),
+            Saved {
+                offset,
+                cached: search.cached,
+                found: !search.places.is_empty(),
+            },
+        );
+        self.lines += 1;
+        if self.lines >= MAX_LOG_LINES {
+            self.compact(Utc::now(), ttl)?;
+        }
+        Ok(())
+    }
+
+    /// Forgets `query`, or every search if None, returning the queries forgotten.
+    fn purge(&mut self, query: Option<&str>, ttl: Duration) -> Result<Vec<String>, Box<dyn Error>> {
+        let purged = match query {
+            Some(query) => self
+                .index
+                .remove_entry(query)
+                .map(|(query, _)| query)
+                .into_iter()
+                .collect(),
+            None => self.index.drain().map(|(query, _)| query).collect(),
+        };
+        self.compact(Utc::now(), ttl)?;
+        Ok(purged)
+    }
+
+    /// Rewrites the file with only the newest `DISK_CAPACITY` fresh searches.
+    fn compact(&mut self, now: DateTime<Utc>, ttl: Duration) -> Result<(), Box<dyn Error>> {
+        let mut kept: Vec<(String, Saved)> = self
+            .index
+            .drain()
+            .filter(|(_, saved)| is_fresh(saved.cached, saved.found, ttl, now))
+            .collect();
+        kept.sort_by_key(|(_, saved)| Reverse(saved.cached));
+        kept.truncate(DISK_CAPACITY);
+        // oldest first, as if they had been appended in order
+        kept.reverse();
+
+        let mut contents = Vec::new();
+        for (query, saved) in kept {
+            let mut line = self.read_line(saved.offset)?;
+            if !line.ends_with(b"\n") {
+                line.push(b'\n');
+            }
+            self.index.insert(
+                query,
+                Saved {
+                    offset: contents.len() as u64,
+                    ..saved
+                },
+            );
+            contents.extend_from_slice(&line);
+        }
+        write_atomic(&self.path, &contents)?;
+        self.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        self.lines = self.index.len();
+        Ok(())
+    }
+
+    fn read_line(&self, offset: u64) -> std::io::Result<Vec<u8>> {
+        let mut reader = BufReader::new(&self.file);
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut line = Vec::new();
+        reader.read_until(b'\n', &mut line)?;
+        Ok(line)
+    }
+}
+
+fn is_fresh(cached: DateTime<Utc>, found: bool, ttl: Duration, now: DateTime<Utc>) -> bool {
+    let ttl = if found { ttl } else { NEGATIVE_TTL.min(ttl) };
+    now - cached < ttl
+}
+
+/// Lowercase with runs of whitespace collapsed and spacing around commas made consistent, so
+/// ` Helsinki ,Finland` and `helsinki, finland` are the same search.
+fn normalise(query: &str) -> String {
+    query
+        .split(',')
+        .map(|part| part.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join(", ")
+        .to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn place(name: &str) -> GeocodedPlace {
+        GeocodedPlace {
+            lon: 24.94,
+            lat: 60.17,
+            name: name.to_string(),
+            country: None,
+            importance: None,
+        }
+    }
+
+    fn search(query: &str, places: Vec<GeocodedPlace>, age: Duration) -> CachedSearch {
+        CachedSearch {
+            query: query.to_string(),
+            places,
+            cached: Utc::now() - age,
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("geocache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn names(search: Option<CachedSearch>) -> Option<Vec<String>> {
+        search.map(|search| search.places.into_iter().map(|place| place.name).collect())
+    }
+
+    #[test]
+    fn log_survives_reopening() {
+        let dir = temp_dir("reopen");
+        let path = dir.join("geocoding.jsonl");
+        let ttl = Duration::days(30);
+        let now = Utc::now();
+
+        let mut log = Log::open(path.clone(), ttl).unwrap();
+        log.append(
+            &search("helsinki", vec![place("Helsinki")], Duration::zero()),
+            ttl,
+        )
+        .unwrap();
+        log.append(&search("nowhere", Vec::new(), Duration::zero()), ttl)
+            .unwrap();
+        log.append(
+            &search("helsinki", vec![place("Helsingfors")], Duration::zero()),
+            ttl,
+        )
+        .unwrap();
+        log.append(&search("stale", Vec::new(), Duration::days(2)), ttl)
+            .unwrap();
+        assert_eq!(log.lines, 4);
+        drop(log);
+
+        // as if a crash cut the last line short
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"query":"tor"#).unwrap();
+
+        let log = Log::open(path.clone(), ttl).unwrap();
+        assert_eq!(
+            names(log.get("helsinki", now, ttl).unwrap()),
+            Some(vec!["Helsingfors".to_string()])
+        );
+        assert_eq!(
+            names(log.get("nowhere", now, ttl).unwrap()),
+            Some(Vec::new())
+        );
+        assert!(log.get("stale", now, ttl).unwrap().is_none());
+        assert!(log.get("tor", now, ttl).unwrap().is_none());
+        // compacted to the fresh searches on opening
+        assert_eq!(log.lines, 2);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn log_purges() {
+        let dir = temp_dir("purge");
+        let path = dir.join("geocoding.jsonl");
+        let ttl = Duration::days(30);
+        let now = Utc::now();
+
+        let mut log = Log::open(path.clone(), ttl).unwrap();
+        for query in ["helsinki", "oulu", "turku"] {
+            log.append(&search(query, vec![place(query)], Duration::zero()), ttl)
+                .unwrap();
+        }
+        assert_eq!(log.purge(Some("oulu"), ttl).unwrap(), ["oulu"]);
+        assert!(log.purge(Some("oulu"), ttl).unwrap().is_empty());
+        assert!(log.get("oulu", now, ttl).unwrap().is_none());
+        assert_eq!(
+            names(log.get("turku", now, ttl).unwrap()),
+            Some(vec!["turku".to_string()])
+        );
+        drop(log);
+
+        let mut log = Log::open(path, ttl).unwrap();
+        assert!(log.get("oulu", now, ttl).unwrap().is_none());
+        assert_eq!(log.purge(None, ttl).unwrap().len(), 2);
+        assert!(log.get("helsinki", now, ttl).unwrap().is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_recent_searches_in_memory() {
+        let cache = GeocodeCache::open(None, Duration::days(30)).unwrap();
+        assert!(cache.get("Helsinki").await.is_none());
+        cache.insert(" Helsinki ,Finland", vec![place("Helsinki")]);
+        assert_eq!(cache.get("helsinki, finland").await.unwrap().len(), 1);
+
+        for i in 0..=MEMORY_CAPACITY {
+            cache.insert(&i.to_string(), Vec::new());
+        }
+        assert!(cache.get("helsinki, finland").await.is_none());
+        assert_eq!(cache.purge(Some("0")).await.unwrap(), 0);
+        assert_eq!(cache.purge(Some("1")).await.unwrap(), 1);
+        assert_eq!(cache.purge(None).await.unwrap(), MEMORY_CAPACITY - 1);
+    }
+}
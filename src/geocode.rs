@@ -0,0 +1,1037 @@
+use chrono::{DateTime, Utc};
+use reverse_geocoder::Record;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        RwLock,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::Mutex,
+    time::{self, Instant},
+};
+
+use crate::{error::AppError, geocache::GeocodeCache, metrics::METRICS};
+
+const USER_AGENT: &str = "pollen.party";
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_NOMINATIM_URL: &str = "https://nominatim.openstreetmap.org";
+const DEFAULT_PHOTON_URL: &str = "https://photon.komoot.io";
+
+/// The public Nominatim and Photon usage policies allow at most one request per second.
+const REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+/// Longest a search waits for its turn.
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Searches allowed to wait for a turn before more are turned away, as many as can have one
+/// within `QUEUE_TIMEOUT`, so those that couldn't are told straight away.
+const MAX_QUEUED: usize = (QUEUE_TIMEOUT.as_millis() / REQUEST_INTERVAL.as_millis()) as usize;
+
+/// The places `reverse_geocoder` finds nearest points among, which the crate doesn't expose, for
+/// the gazetteer to search offline by default.
+const CITIES_CSV: &str = include_str!("../data/cities.csv");
+
+/// Candidates asked of each provider.
+const MAX_RESULTS: usize = 5;
+/// Candidates closer than this in both latitude and longitude are taken to be the same place,
+/// such as a city and the municipality it is in.
+const SAME_PLACE_DEGREES: f32 = 0.1;
+
+/// How far ahead of the next place, in the provider's importance, the best match of a search has
+/// to be to go straight to its forecast.
+const CLEAR_MATCH_GAP: f32 = 0.2;
+
+/// Failures in a row after which a provider is skipped.
+const UNHEALTHY_AFTER: u32 = 3;
+/// How long an unhealthy provider is skipped before it is tried again.
+const UNHEALTHY_COOLDOWN: chrono::Duration = chrono::Duration::minutes(1);
+
+/// A place a search matched.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GeocodedPlace {
+    pub lon: f32,
+    pub lat: f32,
+    /// Full name as the provider gives it, usually with the region and country.
+    pub name: String,
+    pub country: Option<String>,
+    /// How highly the provider ranks the place, from 0 to 1, if it says: Nominatim's importance
+    /// or Pelias's confidence.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub importance: Option<f32>,
+}
+
+impl GeocodedPlace {
+    fn is_near(&self, other: &GeocodedPlace) -> bool {
+        (self.lon - other.lon).abs() < SAME_PLACE_DEGREES
+            && (self.lat - other.lat).abs() < SAME_PLACE_DEGREES
+    }
+}
+
+/// Outcome of the most recent searches sent to a provider.
+#[derive(Serialize, Clone, Default)]
+pub struct GeocoderStatus {
+    pub provider: &'static str,
+    /// Whether searches are being sent to it, false after repeated failures until a cooldown
+    /// has passed.
+    pub healthy: bool,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_failure: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub consecutive_failures: u32,
+    /// Searches waiting for their turn.
+    pub queued: usize,
+}
+
+impl GeocoderStatus {
+    fn is_healthy(&self, now: DateTime<Utc>) -> bool {
+        self.consecutive_failures < UNHEALTHY_AFTER
+            || self
+                .last_failure
+                .is_none_or(|failure| now - failure > UNHEALTHY_COOLDOWN)
+    }
+}
+
+/// Where a provider searches, configured by the `GEOCODERS` secret.
+enum Backend {
+    /// A Nominatim server, the public one by default.
+    Nominatim { url: String },
+    /// A Photon server, komoot's public one by default.
+    Photon { url: String },
+    /// A self-hosted Pelias API.
+    Pelias { url: String },
+    /// Place names from a GeoNames CSV in the `reverse_geocoder` format, searched offline.
+    Gazetteer(Gazetteer),
+}
+
+/// One step of the fallback chain.
+struct Provider {
+    name: &'static str,
+    backend: Backend,
+    timeout: Duration,
+    /// Set for the public servers, which have usage policies.
+    rate_limit: Option<RateLimit>,
+    status: RwLock<GeocoderStatus>,
+}
+
+/// Location search through an ordered chain of providers. Each is tried in turn until one
+/// finds the place, skipping those that are busy or have been failing. Results are cached.
+pub struct Geocoder {
+    providers: Vec<Provider>,
+    client: reqwest::Client,
+    cache: GeocodeCache,
+}
+
+impl Geocoder {
+    /// `config` is a comma separated list of providers in the order to try them, each
+    /// `kind[=location][|timeout in seconds]`: `nominatim` and `photon` with an optional base
+    /// URL, `pelias` with its base URL, and `gazetteer` with an optional path to a CSV to use
+    /// instead of the built-in places. Defaults to `nominatim` falling back to the gazetteer.
+    pub fn from_config(
+        config: Option<&str>,
+        cache: GeocodeCache,
+    ) -> Result<Geocoder, Box<dyn std::error::Error>> {
+        let providers = config
+            .unwrap_or("nominatim,gazetteer")
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(Provider::from_config)
+            .collect::<Result<Vec<_>, _>>()?;
+        if providers.is_empty() {
+            return Err("GEOCODERS lists no providers".into());
+        }
+        Ok(Geocoder {
+            providers,
+            client: reqwest::Client::builder().user_agent(USER_AGENT).build()?,
+            cache,
+        })
+    }
+
+    /// Places the first provider to find any matched, best match first, or the cached result of
+    /// an earlier search. Fails with `AppError::SearchBusy` if providers were busy and none could
+    /// answer.
+    pub async fn search(&self, query: &str) -> Result<Vec<GeocodedPlace>, AppError> {
+        if let Some(places) = self.cache.get(query).await {
+            return Ok(places);
+        }
+        let (places, cacheable) = self.search_providers(query).await?;
+        if cacheable {
+            self.cache.insert(query, places.clone());
+        }
+        Ok(places)
+    }
+
+    /// Forgets the cached result of `query`, or of every search if None.
+    pub async fn purge_cache(&self, query: Option<&str>) -> Result<usize, AppError> {
+        self.cache.purge(query).await
+    }
+
+    /// The places found and whether they can be cached. Finding nothing only can if every
+    /// provider was tried and answered, as one skipped, busy or failing might have found it.
+    async fn search_providers(&self, query: &str) -> Result<(Vec<GeocodedPlace>, bool), AppError> {
+        let now = Utc::now();
+        let healthy: Vec<&Provider> = self
+            .providers
+            .iter()
+            .filter(|provider| provider.status.read().unwrap().is_healthy(now))
+            .collect();
+        // better to try a failing provider than to not search at all
+        let candidates = if healthy.is_empty() {
+            self.providers.iter().collect()
+        } else {
+            healthy
+        };
+
+        let mut every_provider_answered = candidates.len() == self.providers.len();
+        let mut answered = false;
+        let mut busy = false;
+        let mut error = None;
+        for provider in candidates {
+            match provider.search(&self.client, query).await {
+                Ok(places) if !places.is_empty() => return Ok((distinct(places), true)),
+                Ok(_) => answered = true,
+                Err(AppError::SearchBusy) => {
+                    busy = true;
+                    every_provider_answered = false;
+                }
+                Err(err) => {
+                    error = Some(err);
+                    every_provider_answered = false;
+                }
+            }
+        }
+
+        if answered {
+            Ok((Vec::new(), every_provider_answered))
+        } else if busy {
+            Err(AppError::SearchBusy)
+        } else {
+            Err(error.unwrap_or_else(|| AppError::Geocoding("no providers".to_string())))
+        }
+    }
+
+    pub fn status(&self) -> Vec<GeocoderStatus> {
+        let now = Utc::now();
+        self.providers
+            .iter()
+            .map(|provider| {
+                let status = provider.status.read().unwrap();
+                GeocoderStatus {
+                    healthy: status.is_healthy(now),
+                    queued: provider
+                        .rate_limit
+                        .as_ref()
+                        .map_or(0, |limit| limit.queued.load(Ordering::SeqCst)),
+                    ..status.clone()
+                }
+            })
+            .collect()
+    }
+}
+
+impl Provider {
+    fn from_config(entry: &str) -> Result<Provider, Box<dyn std::error::Error>> {
+        let (entry, timeout) = match entry.rsplit_once('|') {
+            Some((entry, seconds)) => (entry, Duration::from_secs_f64(seconds.trim().parse()?)),
+            None => (entry, DEFAULT_TIMEOUT),
+        };
+        let (kind, location) = match entry.split_once('=') {
+            Some((kind, location)) => (kind.trim(), Some(location.trim())),
+            None => (entry.trim(), None),
+        };
+        let base_url = |default: Option<&str>| -> Result<String, String> {
+            location
+                .or(default)
+                .map(|url| url.trim_end_matches('/').to_string())
+                .ok_or_else(|| format!("{} needs a URL, e.g. {}=http://localhost:4000", kind, kind))
+        };
+
+        // only the public servers have usage policies, self-hosted ones are not limited
+        let public = location.is_none();
+        let (name, backend, rate_limited) = match kind {
+            "nominatim" => (
+                "nominatim",
+                Backend::Nominatim {
+                    url: base_url(Some(DEFAULT_NOMINATIM_URL))?,
+                },
+                public,
+            ),
+            "photon" => (
+                "photon",
+                Backend::Photon {
+                    url: base_url(Some(DEFAULT_PHOTON_URL))?,
+                },
+                public,
+            ),
+            "pelias" => (
+                "pelias",
+                Backend::Pelias {
+                    url: base_url(None)?,
+                },
+                false,
+            ),
+            "gazetteer" => {
+                let gazetteer = match location {
+                    Some(path) => Gazetteer::open(Path::new(path))?,
+                    None => Gazetteer::builtin()?,
+                };
+                ("gazetteer", Backend::Gazetteer(gazetteer), false)
+            }
+            other => return Err(format!("unknown geocoder: {}", other).into()),
+        };
+
+        Ok(Provider {
+            name,
+            backend,
+            timeout,
+            rate_limit: rate_limited.then(RateLimit::new),
+            status: RwLock::new(GeocoderStatus {
+                provider: name,
+                ..GeocoderStatus::default()
+            }),
+        })
+    }
+
+    async fn search(
+        &self,
+        client: &reqwest::Client,
+        query: &str,
+    ) -> Result<Vec<GeocodedPlace>, AppError> {
+        if let Some(rate_limit) = &self.rate_limit {
+            rate_limit
+                .wait_for_turn()
+                .await
+                .inspect_err(|_| METRICS.record_geocoder_busy(self.name))?;
+        }
+
+        let started = std::time::Instant::now();
+        let result = match time::timeout(self.timeout, self.backend.search(client, query)).await {
+            Ok(result) => result,
+            Err(_) => Err(format!("timed out after {:?}", self.timeout)),
+        };
+        METRICS.record_geocoder(self.name, result.is_ok(), started.elapsed());
+
+        let mut status = self.status.write().unwrap();
+        match result {
+            Ok(places) => {
+                status.last_success = Some(Utc::now());
+                status.consecutive_failures = 0;
+                Ok(places)
+            }
+            Err(err) => {
+                println!("Location search with {} failed: {}", self.name, err);
+                status.last_failure = Some(Utc::now());
+                status.last_error = Some(err.clone());
+                status.consecutive_failures += 1;
+                Err(AppError::Geocoding(format!("{}: {}", self.name, err)))
+            }
+        }
+    }
+}
+
+impl Backend {
+    async fn search(
+        &self,
+        client: &reqwest::Client,
+        query: &str,
+    ) -> Result<Vec<GeocodedPlace>, String> {
+        let limit = MAX_RESULTS.to_string();
+        match self {
+            Backend::Nominatim { url } => {
+                let places: Vec<NominatimPlace> = get_json(
+                    client,
+                    &format!("{}/search", url),
+                    &[
+                        ("q", query),
+                        ("format", "jsonv2"),
+                        ("addressdetails", "1"),
+                        ("limit", &limit),
+                    ],
+                )
+                .await?;
+                places
+                    .into_iter()
+                    .map(|place| match (place.lon.parse(), place.lat.parse()) {
+                        (Ok(lon), Ok(lat)) => Ok(GeocodedPlace {
+                            lon,
+                            lat,
+                            name: place.display_name,
+                            country: place.address.and_then(|address| address.country),
+                            importance: place.importance,
+                        }),
+                        _ => Err(format!(
+                            "unreadable coordinates {}, {}",
+                            place.lat, place.lon
+                        )),
+                    })
+                    .collect()
+            }
+            Backend::Photon { url } => {
+                let features: FeatureCollection<PhotonProperties> = get_json(
+                    client,
+                    &format!("{}/api", url),
+                    &[("q", query), ("limit", &limit)],
+                )
+                .await?;
+                Ok(features.places(|properties| {
+                    let mut parts: Vec<&str> = Vec::new();
+                    for part in [
+                        &properties.name,
+                        &properties.city,
+                        &properties.state,
+                        &properties.country,
+                    ]
+                    .into_iter()
+                    .flatten()
+                    {
+                        if !parts.contains(&part.as_str()) {
+                            parts.push(part);
+                        }
+                    }
+                    (parts.join(", "), properties.country, None)
+                }))
+            }
+            Backend::Pelias { url } => {
+                let features: FeatureCollection<PeliasProperties> = get_json(
+                    client,
+                    &format!("{}/v1/search", url),
+                    &[("text", query), ("size", &limit)],
+                )
+                .await?;
+                Ok(features.places(|properties| {
+                    (properties.label, properties.country, properties.confidence)
+                }))
+            }
+            Backend::Gazetteer(gazetteer) => Ok(gazetteer.search(query)),
+        }
+    }
+}
+
+async fn get_json<T: for<'de> Deserialize<'de>>(
+    client: &reqwest::Client,
+    url: &str,
+    query: &[(&str, &str)],
+) -> Result<T, String> {
+    let body = client
+        .get(url)
+        .query(query)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|err| err.to_string())?
+        .bytes()
+        .await
+        .map_err(|err| err.to_string())?;
+    serde_json::from_slice(&body).map_err(|err| err.to_string())
+}
+
+/// The place a search clearly meant: the only one found, or one the provider ranks well ahead
+/// of the next, such as Paris in France over the towns in Texas and Ontario named after it.
+/// Providers that don't say how they rank places only have the first.
+pub fn clear_match(places: &[GeocodedPlace]) -> Option<&GeocodedPlace> {
+    match places {
+        [place] => Some(place),
+        [best, next, ..] => match (best.importance, next.importance) {
+            (Some(best_importance), Some(next_importance))
+                if best_importance - next_importance >= CLEAR_MATCH_GAP =>
+            {
+                Some(best)
+            }
+            _ => None,
+        },
+        [] => None,
+    }
+}
+
+/// Candidates in order, leaving out those near a better one.
+fn distinct(places: Vec<GeocodedPlace>) -> Vec<GeocodedPlace> {
+    let mut distinct: Vec<GeocodedPlace> = Vec::new();
+    for place in places {
+        if !distinct.iter().any(|better| better.is_near(&place)) {
+            distinct.push(place);
+        }
+    }
+    distinct
+}
+
+#[derive(Deserialize)]
+struct NominatimPlace {
+    lat: String,
+    lon: String,
+    display_name: String,
+    address: Option<NominatimAddress>,
+    importance: Option<f32>,
+}
+
+#[derive(Deserialize)]
+struct NominatimAddress {
+    country: Option<String>,
+}
+
+/// GeoJSON as Photon and Pelias respond with, `P` being the provider's feature properties.
+#[derive(Deserialize)]
+struct FeatureCollection<P> {
+    features: Vec<Feature<P>>,
+}
+
+#[derive(Deserialize)]
+struct Feature<P> {
+    geometry: Geometry,
+    properties: P,
+}
+
+#[derive(Deserialize)]
+struct PhotonProperties {
+    name: Option<String>,
+    city: Option<String>,
+    state: Option<String>,
+    country: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PeliasProperties {
+    label: String,
+    country: Option<String>,
+    confidence: Option<f32>,
+}
+
+#[derive(Deserialize)]
+struct Geometry {
+    /// Longitude then latitude.
+    coordinates: (f32, f32),
+}
+
+impl<P> FeatureCollection<P> {
+    /// `describe` gives the name, country and importance of a feature from its properties.
+    fn places(
+        self,
+        describe: impl Fn(P) -> (String, Option<String>, Option<f32>),
+    ) -> Vec<GeocodedPlace> {
+        self.features
+            .into_iter()
+            .map(|feature| {
+                let (name, country, importance) = describe(feature.properties);
+                GeocodedPlace {
+                    lon: feature.geometry.coordinates.0,
+                    lat: feature.geometry.coordinates.1,
+                    name,
+                    country,
+                    importance,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Places by name, for searching without a network. Matches the first part of the query
+/// against place names and keeps the places whose region or country code match most of the
+/// rest, so `Sant Julia de Loria, AD` only finds the one in Andorra.
+struct Gazetteer {
+    records: Vec<Record>,
+    by_name: HashMap<String, Vec<usize>>,
+}
+
+impl Gazetteer {
+    fn builtin() -> Result<Gazetteer, Box<dyn std::error::Error>> {
+        Gazetteer::from_reader(csv::Reader::from_reader(CITIES_CSV.as_bytes()))
+    }
+
+    fn open(path: &Path) -> Result<Gazetteer, Box<dyn std::error::Error>> {
+        Gazetteer::from_reader(csv::Reader::from_path(path)?)
+    }
+
+    fn from_reader<R: std::io::Read>(
+        mut reader: csv::Reader<R>,
+    ) -> Result<Gazetteer, Box<dyn std::error::Error>> {
+        let records = reader.deserialize().collect::<Result<Vec<Record>, _>>()?;
+        let mut by_name: HashMap<String, Vec<usize>> = HashMap::new();
+        for (index, record) in records.iter().enumerate() {
+            by_name
+                .entry(record.name.to_lowercase())
+                .or_default()
+                .push(index);
+        }
+        Ok(Gazetteer { records, by_name })
+    }
+
+    fn search(&self, query: &str) -> Vec<GeocodedPlace> {
+        let mut terms = query.split(',').map(|term| term.trim().to_lowercase());
+        let Some(records) = terms.next().and_then(|name| self.by_name.get(&name)) else {
+            return Vec::new();
+        };
+        let qualifiers: Vec<String> = terms.filter(|term| !term.is_empty()).collect();
+
+        let matched = |record: &Record| {
+            qualifiers
+                .iter()
+                .filter(|qualifier| {
+                    [&record.admin1, &record.admin2, &record.cc]
+                        .iter()
+                        .any(|field| field.to_lowercase() == **qualifier)
+                })
+                .count()
+        };
+        let records: Vec<&Record> = records.iter().map(|index| &self.records[*index]).collect();
+        let best = records.iter().map(|record| matched(record)).max();
+        records
+            .into_iter()
+            .filter(|record| Some(matched(record)) == best)
+            .take(MAX_RESULTS)
+            .map(|record| GeocodedPlace {
+                lon: record.lon as f32,
+                lat: record.lat as f32,
+                name: [&record.name, &record.admin1, &record.admin2]
+                    .into_iter()
+                    .filter(|part| !part.is_empty())
+                    .map(String::as_str)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                country: Some(record.cc.clone()),
+                importance: None,
+            })
+            .collect()
+    }
+}
+
+/// Spaces requests `REQUEST_INTERVAL` apart. Searches queue for their turn in arrival order,
+/// and are turned away with `AppError::SearchBusy` when the queue is full or the wait too long.
+struct RateLimit {
+    /// When the next request may be sent. Tokio's mutex is fair, so waiting on it is the queue.
+    next_request: Mutex<Instant>,
+    queued: AtomicUsize,
+}
+
+/// Takes a search off the queue count however it leaves, including by timing out.
+struct QueuePlace<'a>(&'a AtomicUsize);
+
+impl Drop for QueuePlace<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl RateLimit {
+    fn new() -> RateLimit {
+        RateLimit {
+            next_request: Mutex::new(Instant::now()),
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    async fn wait_for_turn(&self) -> Result<(), AppError> {
+        if self.queued.fetch_add(1, Ordering::SeqCst) >= MAX_QUEUED {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            return Err(AppError::SearchBusy);
+        }
+        let _place = QueuePlace(&self.queued);
+
+        time::timeout(QUEUE_TIMEOUT, async {
+            // held until the slot comes round, so later searches stay behind this one
+            let mut next_request = self.next_request.lock().await;
+            time::sleep_until(*next_request).await;
+            *next_request = Instant::now() + REQUEST_INTERVAL;
+        })
+        .await
+        .map_err(|_| AppError::SearchBusy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::Query, http::StatusCode, response::IntoResponse, routing::get, Router};
+    use serde_json::{json, Value};
+    use std::sync::{atomic::AtomicU32, Arc};
+    use tokio::net::TcpListener;
+
+    /// Serves `app` on a local port, returning its base URL.
+    async fn stub(app: Router) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        url
+    }
+
+    /// Canned responses for `Paris`, and nothing for anything else.
+    fn providers() -> Router {
+        Router::new()
+            .route(
+                "/search",
+                get(|Query(params): Query<HashMap<String, String>>| async move {
+                    axum::Json(if params["q"] == "Paris" {
+                        json!([
+                            {
+                                "lat": "48.8534951",
+                                "lon": "2.3483915",
+                                "display_name": "Paris, Île-de-France, France métropolitaine, France",
+                                "address": { "city": "Paris", "country": "France" },
+                                "importance": 0.8845663630228834,
+                            },
+                            {
+                                "lat": "48.8588897",
+                                "lon": "2.3200410",
+                                "display_name": "Paris, Île-de-France, France",
+                                "address": { "country": "France" },
+                                "importance": 0.7,
+                            },
+                            {
+                                "lat": "33.6617962",
+                                "lon": "-95.5555130",
+                                "display_name": "Paris, Lamar County, Texas, United States",
+                                "address": { "country": "United States" },
+                                "importance": 0.5468727515226075,
+                            },
+                        ])
+                    } else {
+                        json!([])
+                    })
+                }),
+            )
+            .route(
+                "/api",
+                get(|Query(params): Query<HashMap<String, String>>| async move {
+                    axum::Json(json!({
+                        "type": "FeatureCollection",
+                        "features": if params["q"] == "Paris" {
+                            json!([{
+                                "type": "Feature",
+                                "geometry": { "type": "Point", "coordinates": [2.3483915, 48.8534951] },
+                                "properties": {
+                                    "name": "Paris",
+                                    "city": "Paris",
+                                    "state": "Ile-de-France",
+                                    "country": "France",
+                                },
+                            }])
+                        } else {
+                            json!([])
+                        },
+                    }))
+                }),
+            )
+            .route(
+                "/v1/search",
+                get(|Query(params): Query<HashMap<String, String>>| async move {
+                    axum::Json(json!({
+                        "type": "FeatureCollection",
+                        "features": if params["text"] == "Paris" {
+                            json!([{
+                                "type": "Feature",
+                                "geometry": { "type": "Point", "coordinates": [2.3483915, 48.8534951] },
+                                "properties": {
+                                    "label": "Paris, France",
+                                    "country": "France",
+                                    "confidence": 1,
+                                },
+                            }])
+                        } else {
+                            json!([])
+                        },
+                    }))
+                }),
+            )
+    }
+
+    /// A server failing every request, counting them.
+    async fn failing() -> (String, Arc<AtomicU32>) {
+        let hits = Arc::new(AtomicU32::new(0));
+        let counter = Arc::clone(&hits);
+        let url = stub(Router::new().fallback(move || async move {
+            counter.fetch_add(1, Ordering::SeqCst);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }))
+        .await;
+        (url, hits)
+    }
+
+    fn geocoder(config: &str) -> Geocoder {
+        let cache = GeocodeCache::open(None, chrono::Duration::days(1)).unwrap();
+        Geocoder::from_config(Some(config), cache).unwrap()
+    }
+
+    fn names(places: &[GeocodedPlace]) -> Vec<&str> {
+        places.iter().map(|place| place.name.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn reads_each_provider() {
+        let url = stub(providers()).await;
+
+        let places = geocoder(&format!("nominatim={}", url))
+            .search("Paris")
+            .await
+            .unwrap();
+        // the second is the same place as the first
+        assert_eq!(
+            names(&places),
+            [
+                "Paris, Île-de-France, France métropolitaine, France",
+                "Paris, Lamar County, Texas, United States"
+            ]
+        );
+        assert_eq!(places[0].country.as_deref(), Some("France"));
+        assert_eq!(places[0].importance, Some(0.88456637));
+        assert_eq!((places[1].lon, places[1].lat), (-95.55551, 33.661797));
+        assert!(clear_match(&places).is_some());
+
+        let places = geocoder(&format!("photon={}", url))
+            .search("Paris")
+            .await
+            .unwrap();
+        assert_eq!(names(&places), ["Paris, Ile-de-France, France"]);
+        assert_eq!((places[0].lon, places[0].lat), (2.3483915, 48.853495));
+        assert_eq!(places[0].importance, None);
+
+        let places = geocoder(&format!("pelias={}", url))
+            .search("Paris")
+            .await
+            .unwrap();
+        assert_eq!(names(&places), ["Paris, France"]);
+        assert_eq!(places[0].country.as_deref(), Some("France"));
+        assert_eq!(places[0].importance, Some(1.0));
+    }
+
+    #[tokio::test]
+    async fn falls_back_after_server_error() {
+        let (failing, hits) = failing().await;
+        let working = stub(providers()).await;
+        let geocoder = geocoder(&format!("nominatim={},photon={}", failing, working));
+
+        let places = geocoder.search("Paris").await.unwrap();
+        assert_eq!(names(&places), ["Paris, Ile-de-France, France"]);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        let status = geocoder.status();
+        assert_eq!(status[0].consecutive_failures, 1);
+        assert!(status[0].last_error.as_deref().unwrap().contains("500"));
+        assert!(status[1].last_success.is_some());
+    }
+
+    #[tokio::test]
+    async fn falls_back_after_timeout() {
+        let slow = stub(Router::new().fallback(|| async {
+            time::sleep(Duration::from_secs(5)).await;
+            axum::Json(Value::Array(Vec::new()))
+        }))
+        .await;
+        let working = stub(providers()).await;
+        let geocoder = geocoder(&format!("nominatim={}|0.1,pelias={}", slow, working));
+
+        let places = geocoder.search("Paris").await.unwrap();
+        assert_eq!(names(&places), ["Paris, France"]);
+        let status = geocoder.status();
+        assert!(status[0]
+            .last_error
+            .as_deref()
+            .unwrap()
+            .contains("timed out"));
+    }
+
+    #[tokio::test]
+    async fn fails_when_every_provider_fails() {
+        let (failing, _) = failing().await;
+        let geocoder = geocoder(&format!("nominatim={},pelias={}", failing, failing));
+        assert!(matches!(
+            geocoder.search("Paris").await,
+            Err(AppError::Geocoding(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn skips_unhealthy_providers_until_cooldown() {
+        let (failing, hits) = failing().await;
+        let working = stub(providers()).await;
+        let geocoder = geocoder(&format!("nominatim={},photon={}", failing, working));
+
+        for _ in 0..UNHEALTHY_AFTER {
+            geocoder.search("Paris").await.unwrap();
+            geocoder.purge_cache(None).await.unwrap();
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), UNHEALTHY_AFTER);
+        assert!(!geocoder.status()[0].healthy);
+
+        geocoder.search("Paris").await.unwrap();
+        geocoder.purge_cache(None).await.unwrap();
+        assert_eq!(hits.load(Ordering::SeqCst), UNHEALTHY_AFTER);
+
+        // as if the cooldown has passed
+        geocoder.providers[0].status.write().unwrap().last_failure =
+            Some(Utc::now() - UNHEALTHY_COOLDOWN - chrono::Duration::seconds(1));
+        assert!(geocoder.status()[0].healthy);
+        geocoder.search("Paris").await.unwrap();
+        assert_eq!(hits.load(Ordering::SeqCst), UNHEALTHY_AFTER + 1);
+    }
+
+    #[tokio::test]
+    async fn tries_unhealthy_providers_when_all_are() {
+        let (failing, hits) = failing().await;
+        let geocoder = geocoder(&format!("pelias={}", failing));
+        for _ in 0..UNHEALTHY_AFTER + 1 {
+            assert!(geocoder.search("Paris").await.is_err());
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), UNHEALTHY_AFTER + 1);
+    }
+
+    #[tokio::test]
+    async fn caches_nothing_found_only_if_every_provider_answered() {
+        let (failing, hits) = failing().await;
+        let working = stub(providers()).await;
+
+        let partly_failing = geocoder(&format!("nominatim={},photon={}", failing, working));
+        assert!(partly_failing.search("Nowhere").await.unwrap().is_empty());
+        assert!(partly_failing.search("Nowhere").await.unwrap().is_empty());
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        // found places are cached whichever provider found them
+        partly_failing.search("Paris").await.unwrap();
+        partly_failing.search("Paris").await.unwrap();
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+
+        let answering = geocoder(&format!("photon={},pelias={}", working, working));
+        assert!(answering.search("Nowhere").await.unwrap().is_empty());
+        assert!(answering
+            .cache
+            .get("Nowhere")
+            .await
+            .is_some_and(|places| places.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn turns_away_searches_that_would_time_out() {
+        // the last search let in still gets its turn in time
+        assert!(REQUEST_INTERVAL * (MAX_QUEUED as u32 - 1) < QUEUE_TIMEOUT);
+
+        let rate_limit = RateLimit::new();
+        rate_limit.queued.store(MAX_QUEUED, Ordering::SeqCst);
+        let started = Instant::now();
+        assert!(matches!(
+            rate_limit.wait_for_turn().await,
+            Err(AppError::SearchBusy)
+        ));
+        assert!(started.elapsed() < Duration::from_millis(100));
+        assert_eq!(rate_limit.queued.load(Ordering::SeqCst), MAX_QUEUED);
+
+        rate_limit.queued.store(0, Ordering::SeqCst);
+        rate_limit.wait_for_turn().await.unwrap();
+        assert_eq!(rate_limit.queued.load(Ordering::SeqCst), 0);
+    }
+
+    fn place(name: &str, lon: f32, lat: f32) -> GeocodedPlace {
+        GeocodedPlace {
+            lon,
+            lat,
+            name: name.to_string(),
+            country: None,
+            importance: None,
+        }
+    }
+
+    fn ranked(name: &str, importance: f32) -> GeocodedPlace {
+        GeocodedPlace {
+            importance: Some(importance),
+            ..place(name, 0.0, 0.0)
+        }
+    }
+
+    #[test]
+    fn clear_match_needs_a_lead_in_importance() {
+        let name = |places: &[GeocodedPlace]| clear_match(places).map(|place| place.name.clone());
+        assert_eq!(name(&[]), None);
+        assert_eq!(name(&[place("Only", 0.0, 0.0)]), Some("Only".to_string()));
+        assert_eq!(
+            name(&[ranked("Paris, France", 0.88), ranked("Paris, Texas", 0.55)]),
+            Some("Paris, France".to_string())
+        );
+        // too close to call
+        assert_eq!(
+            name(&[
+                ranked("Cambridge, England", 0.73),
+                ranked("Cambridge, Massachusetts", 0.7)
+            ]),
+            None
+        );
+        // without importance only the order is known
+        assert_eq!(
+            name(&[
+                place("Paris, France", 0.0, 0.0),
+                place("Paris, Texas", 0.0, 0.0)
+            ]),
+            None
+        );
+        assert_eq!(
+            name(&[
+                ranked("Paris, France", 0.88),
+                place("Paris, Texas", 0.0, 0.0)
+            ]),
+            None
+        );
+    }
+
+    #[test]
+    fn distinct_drops_places_near_better_ones() {
+        let places = distinct(vec![
+            place("Helsinki", 24.94, 60.17),
+            place("Helsinki municipality", 24.99, 60.2),
+            place("Helsinki, Arkansas", -90.7, 35.5),
+            place("Just far enough", 24.84, 60.17),
+        ]);
+        assert_eq!(
+            names(&places),
+            ["Helsinki", "Helsinki, Arkansas", "Just far enough"]
+        );
+    }
+
+    fn gazetteer() -> Gazetteer {
+        let path = std::env::temp_dir().join(format!("gazetteer-{}.csv", std::process::id()));
+        std::fs::write(
+            &path,
+            "lat,lon,name,admin1,admin2,cc\n\
+             52.2,0.11667,Cambridge,England,Cambridgeshire,GB\n\
+             42.3751,-71.10561,Cambridge,Massachusetts,Middlesex County,US\n\
+             43.3601,-80.31269,Cambridge,Ontario,,CA\n\
+             42.46372,1.49129,Sant Julia de Loria,Sant Julia de Loria,,AD\n",
+        )
+        .unwrap();
+        let gazetteer = Gazetteer::open(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        gazetteer
+    }
+
+    #[test]
+    fn builtin_gazetteer_has_the_reverse_geocoder_places() {
+        let gazetteer = Gazetteer::builtin().unwrap();
+        let places = gazetteer.search("Helsinki");
+        assert_eq!(names(&places), ["Helsinki, Uusimaa, Helsinki"]);
+        assert_eq!(places[0].country.as_deref(), Some("FI"));
+        assert_eq!(
+            names(&gazetteer.search("Paris, FR")),
+            ["Paris, Ile-de-France, Paris"]
+        );
+        assert_eq!(gazetteer.search("Paris").len(), MAX_RESULTS);
+    }
+
+    #[test]
+    fn gazetteer_matches_qualifiers() {
+        let gazetteer = gazetteer();
+        assert_eq!(
+            names(&gazetteer.search("cambridge")),
+            [
+                "Cambridge, England, Cambridgeshire",
+                "Cambridge, Massachusetts, Middlesex County",
+                "Cambridge, Ontario"
+            ]
+        );
+        assert_eq!(
+            names(&gazetteer.search(" Cambridge , massachusetts")),
+            ["Cambridge, Massachusetts, Middlesex County"]
+        );
+        let places = gazetteer.search("Cambridge, GB");
+        assert_eq!(names(&places), ["Cambridge, England, Cambridgeshire"]);
+        assert_eq!(places[0].country.as_deref(), Some("GB"));
+        // qualifiers matching nothing don't rule places out
+        assert_eq!(gazetteer.search("Cambridge, Narnia").len(), 3);
+        assert!(gazetteer.search("Camb").is_empty());
+        assert!(gazetteer.search("").is_empty());
+    }
+}
@@ -1,20 +1,43 @@
 use axum::{
-    extract::{Query, State},
-    http::HeaderMap,
-    response::{IntoResponse, Redirect, Response},
-    Json,
+    extract::{
+        rejection::{FormRejection, JsonRejection, QueryRejection},
+        Path, Query, State,
+    },
+    http::{HeaderMap, HeaderValue},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Redirect, Response,
+    },
+    Form, Json,
 };
-use chrono::{Local, Locale, NaiveTime};
+use chrono::{DateTime, Locale, Utc};
 use chrono_tz::Tz;
+use futures_util::{stream, StreamExt};
+use maud::html;
 use reqwest::{header, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{cmp::min, str::FromStr, sync::Arc};
+use std::{cmp::min, sync::Arc};
 
 use crate::{
-    html::{forecast, home, page},
-    phone::get_phone_text,
-    silam::Pollen,
+    calendar::high_pollen_calendar,
+    daily::{summarise_days, DaySummary},
+    email::{send_confirmation, NewEmailSubscription, Schedule},
+    error::{ApiError, AppError},
+    feed::{outlook_feed, Outlook},
+    format::{ApiResponse, Format},
+    geocode::{clear_match, GeocodedPlace, GeocoderStatus},
+    html::{
+        email_confirmed, email_form, email_sent, forecast, forecast_query, home, outside_coverage,
+        page, push_form, search_busy, search_results, unsubscribe_form, unsubscribed,
+    },
+    phone::{get_outlook_text, get_phone_text},
+    push::NewPushSubscription,
+    refresh::RefreshStatus,
+    secret,
+    silam::{Interpolation, Pollen, PollenIndex, PollenType, Silam},
+    webhooks::{notify_webhook, NewWebhook, Webhook},
+    window::{start_of_day, ForecastWindow},
     AppState,
 };
 
@@ -25,28 +48,70 @@ pub struct IndexParams {
     lon: Option<f32>,
     lat: Option<f32>,
     loc: Option<String>,
+    species: Option<String>,
+    interpolation: Option<Interpolation>,
 }
 
 pub async fn index(
-    Query(params): Query<IndexParams>,
+    params: Result<Query<IndexParams>, QueryRejection>,
     State(state): State<Arc<AppState>>,
 ) -> Response {
+    // one snapshot for the whole request, so a refresh can't swap the data out midway
+    let silam = state.silam.load_full();
+    match index_response(params, &state, &silam).await {
+        Ok(response) => response,
+        Err(err) => err.into_html(&silam).into_response(),
+    }
+}
+
+async fn index_response(
+    params: Result<Query<IndexParams>, QueryRejection>,
+    state: &AppState,
+    silam: &Silam,
+) -> Result<Response, AppError> {
+    let Query(params) = params?;
+
     if let Some(loc) = params.loc {
-        let nominatim_response = match state.nominatim.search(&loc).await {
-            Ok(res) => res,
-            Err(_) => return Redirect::temporary("/").into_response(),
+        let places = match state.geocoder.search(&loc).await {
+            Err(AppError::SearchBusy) => {
+                let body = page(true, silam.fetch_time, silam.run_time, search_busy(&loc));
+                return Ok((
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    [(header::RETRY_AFTER, HeaderValue::from_static("5"))],
+                    body,
+                )
+                    .into_response());
+            }
+            result => result?,
         };
-        let place = match nominatim_response.first() {
-            Some(first) => first,
-            None => return Redirect::temporary("/").into_response(),
+        let forecast_url = |place: &GeocodedPlace| {
+            format!(
+                "/?lat={:.2$}&lon={:.2$}",
+                place.lat, place.lon, DECIMAL_PLACES,
+            )
         };
-        return Redirect::permanent(&format!(
-            "/?lat={:.2$}&lon={:.2$}",
-            place.lat.parse::<f32>().unwrap(),
-            place.lon.parse::<f32>().unwrap(),
-            DECIMAL_PLACES,
-        ))
-        .into_response();
+        if places.is_empty() {
+            return Ok(Redirect::temporary("/").into_response());
+        }
+        // temporary, as what a search finds depends on the providers and the cache
+        if let Some(place) = clear_match(&places) {
+            return Ok(Redirect::temporary(&forecast_url(place)).into_response());
+        }
+        // let the user pick rather than guess which Paris they meant
+        let places: Vec<_> = places
+            .iter()
+            .map(|place| {
+                let covered = silam.covers(&place.lon, &place.lat);
+                (forecast_url(place), place, covered)
+            })
+            .collect();
+        return Ok(page(
+            true,
+            silam.fetch_time,
+            silam.run_time,
+            search_results(&loc, &places),
+        )
+        .into_response());
     }
 
     let mut headers = HeaderMap::new();
@@ -57,20 +122,16 @@ pub async fn index(
         ..
     } = params
     {
-        let (rounded_lon, rounded_lat) = (
-            format!("{:.1$}", lon, DECIMAL_PLACES),
-            format!("{:.1$}", lat, DECIMAL_PLACES),
-        );
-
-        if rounded_lon.parse::<f32>().unwrap() != lon || rounded_lat.parse::<f32>().unwrap() != lat
-        {
-            return Redirect::permanent(&format!(
+        check_coords(lon, lat)?;
+        if !is_rounded(lon) || !is_rounded(lat) {
+            return Ok(Redirect::permanent(&format!(
                 "/?lat={:.2$}&lon={:.2$}",
                 lat, lon, DECIMAL_PLACES
             ))
-            .into_response();
+            .into_response());
         }
 
+        let interpolation = params.interpolation.unwrap_or_default();
         let location = state
             .reverse_geocoder
             .search((lat.into(), lon.into()))
@@ -80,215 +141,1006 @@ pub async fn index(
             location.name, location.admin1, location.admin2, location.cc, lat, lon, DECIMAL_PLACES,
         );
 
-        let tz: Tz = state
-            .finder
-            .get_tz_name(lon.into(), lat.into())
-            .parse()
-            .unwrap();
-
-        let start_index: usize = (Local::now()
-            .with_timezone(&tz)
-            .with_time(NaiveTime::from_hms_opt(0, 0, 0).unwrap())
-            .unwrap()
-            .to_utc()
-            - state.silam.read().unwrap().start_time)
-            .num_hours()
-            .try_into()
-            .unwrap();
-        let end_index = start_index + 72;
-        let pollen = state
-            .silam
-            .read()
-            .unwrap()
-            .get_at_coords(&lon, &lat)
-            .drain(start_index..end_index)
-            .collect();
+        let tz = state.timezone(lon, lat)?;
+        let window = ForecastWindow::three_days(&tz);
+        let pollen = match silam.get_window(&lon, &lat, interpolation, &window) {
+            Ok(pollen) => pollen,
+            Err(AppError::OutsideCoverage) => {
+                let body = page(
+                    true,
+                    silam.fetch_time,
+                    silam.run_time,
+                    outside_coverage(&location_heading),
+                );
+                return Ok((StatusCode::UNPROCESSABLE_ENTITY, body).into_response());
+            }
+            Err(err) => return Err(err),
+        };
 
-        let locale = Locale::from_str("en_GB").unwrap();
+        let locale = Locale::en_GB;
+        let query = forecast_query(lon, lat, interpolation);
+        let species = params.species.as_deref().and_then(PollenType::from_name);
 
         let body = page(
             true,
-            state.silam.read().unwrap().fetch_time,
-            forecast(&pollen, &location_heading, &tz, &locale),
+            silam.fetch_time,
+            silam.run_time,
+            html! {
+                (forecast(
+                    &pollen,
+                    &location_heading,
+                    &tz,
+                    &locale,
+                    (lon, lat),
+                    species,
+                    interpolation,
+                ))
+                (push_form(&query, &state.push.public_key()))
+                @if state.email.is_enabled() {
+                    (email_form(&query))
+                }
+            },
         );
 
-        let max_age = get_max_age(&state.silam.read().unwrap().time_until_stale(), &tz);
-        let cache_control = format!("s-max-age={}, public, immutable, must-revalidate", max_age);
-        headers.insert(header::CACHE_CONTROL, cache_control.parse().unwrap());
+        let max_age = get_max_age(&silam.time_until_stale(), &tz);
+        headers.insert(header::CACHE_CONTROL, cache_control(max_age));
 
-        return (headers, body).into_response();
+        return Ok((headers, body).into_response());
     }
 
-    let body = page(false, state.silam.read().unwrap().fetch_time, home());
+    let body = page(false, silam.fetch_time, silam.run_time, home());
 
-    let cache_control = format!(
-        "s-max-age={}, public, immutable, must-revalidate",
-        &state.silam.read().unwrap().time_until_stale().num_seconds()
+    headers.insert(
+        header::CACHE_CONTROL,
+        cache_control(silam.time_until_stale().num_seconds()),
     );
-    headers.insert(header::CACHE_CONTROL, cache_control.parse().unwrap());
 
-    (headers, body).into_response()
+    Ok((headers, body).into_response())
+}
+
+fn cache_control(max_age: i64) -> HeaderValue {
+    HeaderValue::from_str(&format!(
+        "s-max-age={}, public, immutable, must-revalidate",
+        max_age
+    ))
+    .expect("formatted Cache-Control is a valid header value")
 }
 
 fn get_max_age(time_until_stale: &chrono::Duration, tz: &Tz) -> i64 {
     let seconds_until_stale = time_until_stale.num_seconds();
-    let now = Local::now().with_timezone(tz);
-    let time_until_local_midnight = (now + chrono::Duration::days(1))
-        .with_time(NaiveTime::from_hms_opt(0, 0, 0).unwrap())
-        .unwrap()
-        - now;
-    let seconds_until_local_midnight = time_until_local_midnight.num_seconds();
+    let now = Utc::now();
+    let today = now.with_timezone(tz).date_naive();
+    let seconds_until_local_midnight = match today.succ_opt() {
+        Some(tomorrow) => (start_of_day(tomorrow, tz) - now).num_seconds(),
+        None => seconds_until_stale,
+    };
     min(seconds_until_stale, seconds_until_local_midnight)
 }
 
+fn check_coords(lon: f32, lat: f32) -> Result<(), AppError> {
+    if (-180.0..=180.0).contains(&lon) && (-90.0..=90.0).contains(&lat) {
+        Ok(())
+    } else {
+        Err(AppError::InvalidCoordinates)
+    }
+}
+
+/// Whether the value has at most `DECIMAL_PLACES` decimal places.
+fn is_rounded(value: f32) -> bool {
+    format!("{:.1$}", value, DECIMAL_PLACES)
+        .parse::<f32>()
+        .is_ok_and(|rounded| rounded == value)
+}
+
 #[derive(Deserialize)]
 pub struct ApiParams {
     lon: Option<f32>,
     lat: Option<f32>,
+    interpolation: Option<Interpolation>,
+    start: Option<String>,
+    end: Option<String>,
+    hours: Option<usize>,
+    days: Option<u64>,
+    format: Option<Format>,
 }
 
 #[derive(Serialize)]
-pub struct ApiError {
-    msg: String,
+pub struct DailyResponse {
+    attribution: String,
+    fetch_time: DateTime<Utc>,
+    run_time: DateTime<Utc>,
+    location: String,
+    timezone: String,
+    days: Vec<DaySummary>,
+}
+
+/// Days summarised by `/api/daily` when no window length is given: today and the next two.
+const DAILY_DAYS: u64 = 3;
+
+pub async fn api(
+    request_headers: HeaderMap,
+    params: Result<Query<ApiParams>, QueryRejection>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let silam = state.silam.load_full();
+    let mut response = with_cors(api_response(&request_headers, params, &state, &silam));
+    response
+        .headers_mut()
+        .insert(header::VARY, HeaderValue::from_static("Accept"));
+    response
+}
+
+pub async fn api_daily(
+    params: Result<Query<ApiParams>, QueryRejection>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let silam = state.silam.load_full();
+    with_cors(api_daily_response(params, &state, &silam))
+}
+
+fn with_cors(result: Result<Response, AppError>) -> Response {
+    let mut response = match result {
+        Ok(response) => response,
+        Err(err) => err.into_response(),
+    };
+    response.headers_mut().insert(
+        header::ACCESS_CONTROL_ALLOW_ORIGIN,
+        HeaderValue::from_static("*"),
+    );
+    response
+}
+
+#[derive(Deserialize, Clone)]
+pub struct EventsParams {
+    lon: Option<f32>,
+    lat: Option<f32>,
+    interpolation: Option<Interpolation>,
+    start: Option<String>,
+    end: Option<String>,
+    hours: Option<usize>,
+    days: Option<u64>,
 }
 
+/// What `/api/events` sends when data is installed.
 #[derive(Serialize)]
-pub struct ApiResponse {
+struct DataEvent {
     attribution: String,
-    location: String,
-    pollen: Vec<Pollen>,
+    fetch_time: DateTime<Utc>,
+    run_time: DateTime<Utc>,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    /// Forecast for the location in the query, if one was given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    forecast: Option<ApiResponse>,
+    /// Why there is no forecast this time, such as the window being outside the new data.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ApiError>,
 }
 
-pub async fn api(Query(params): Query<ApiParams>, State(state): State<Arc<AppState>>) -> Response {
-    let mut headers = HeaderMap::new();
-    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*".parse().unwrap());
+/// Server-sent events stream with a `silam` event for the data loaded on connecting and another
+/// each time new data is installed.
+pub async fn api_events(
+    params: Result<Query<EventsParams>, QueryRejection>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    with_cors(api_events_response(params, state))
+}
 
-    if let ApiParams {
-        lon: Some(lon),
-        lat: Some(lat),
-        ..
-    } = params
-    {
-        let (rounded_lon, rounded_lat) = (
-            format!("{:.1$}", lon, DECIMAL_PLACES),
-            format!("{:.1$}", lat, DECIMAL_PLACES),
-        );
+fn api_events_response(
+    params: Result<Query<EventsParams>, QueryRejection>,
+    state: Arc<AppState>,
+) -> Result<Response, AppError> {
+    let Query(params) = params?;
+    if params.lon.is_some() || params.lat.is_some() {
+        // reject a bad location now rather than in every event
+        api_location(params.lon, params.lat, params.interpolation, &state)?;
+    }
 
-        if rounded_lon.parse::<f32>().unwrap() != lon || rounded_lat.parse::<f32>().unwrap() != lat
-        {
-            return (
-                StatusCode::BAD_REQUEST,
-                Json(ApiError {
-                    msg: format!(
-                        "Coordinates accept maximum {} decimal places",
-                        DECIMAL_PLACES
-                    ),
-                }),
-            )
-                .into_response();
-        }
+    let installed = state.silam_installed.subscribe();
+    let first = data_event(&state, &params);
+    let updates = stream::unfold(
+        (installed, state, params),
+        |(mut installed, state, params)| async move {
+            // only fails once the server is shutting down
+            installed.changed().await.ok()?;
+            let event = data_event(&state, &params);
+            Some((event, (installed, state, params)))
+        },
+    );
 
-        let location = state
-            .reverse_geocoder
-            .search((lat.into(), lon.into()))
-            .record;
-        let location_string = format!(
-            "{}, {}, {}, {}",
-            location.name, location.admin1, location.admin2, location.cc,
+    Ok(Sse::new(stream::once(async { first }).chain(updates))
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+fn data_event(state: &AppState, params: &EventsParams) -> Result<Event, axum::Error> {
+    let silam = state.silam.load_full();
+    let mut data = DataEvent {
+        attribution: "Data from FMI SILAM and EAN".to_string(),
+        fetch_time: silam.fetch_time,
+        run_time: silam.run_time,
+        start_time: silam.start_time,
+        end_time: silam.end_time(),
+        forecast: None,
+        error: None,
+    };
+
+    if params.lon.is_some() || params.lat.is_some() {
+        let forecast = api_location(params.lon, params.lat, params.interpolation, state).and_then(
+            |location| {
+                let window = ForecastWindow::from_params(
+                    params.start.as_deref(),
+                    params.end.as_deref(),
+                    params.hours,
+                    params.days,
+                    &location.tz,
+                    &silam,
+                )?;
+                let pollen = silam.get_window(
+                    &location.lon,
+                    &location.lat,
+                    location.interpolation,
+                    &window,
+                )?;
+                Ok(ApiResponse {
+                    attribution: data.attribution.clone(),
+                    fetch_time: silam.fetch_time,
+                    run_time: silam.run_time,
+                    location: location.name,
+                    pollen,
+                })
+            },
         );
+        match forecast {
+            Ok(forecast) => data.forecast = Some(forecast),
+            Err(err) => data.error = Some(err.to_api_error()),
+        }
+    }
 
-        let tz: Tz = state
-            .finder
-            .get_tz_name(lon.into(), lat.into())
-            .parse()
-            .unwrap();
-
-        let start_index: usize = (Local::now()
-            .with_timezone(&tz)
-            .with_time(NaiveTime::from_hms_opt(0, 0, 0).unwrap())
-            .unwrap()
-            .to_utc()
-            - state.silam.read().unwrap().start_time)
-            .num_hours()
-            .try_into()
-            .unwrap();
-        let end_index = start_index + 72;
-        let pollen = state
-            .silam
-            .read()
-            .unwrap()
-            .get_at_coords(&lon, &lat)
-            .drain(start_index..end_index)
-            .collect();
+    Event::default()
+        .event("silam")
+        .id(silam.fetch_time.to_rfc3339())
+        .json_data(data)
+}
+
+/// A location requested through the API, checked and looked up.
+struct ApiLocation {
+    lon: f32,
+    lat: f32,
+    interpolation: Interpolation,
+    name: String,
+    tz: Tz,
+}
+
+fn api_location(
+    lon: Option<f32>,
+    lat: Option<f32>,
+    interpolation: Option<Interpolation>,
+    state: &AppState,
+) -> Result<ApiLocation, AppError> {
+    let (lon, lat) = match (lon, lat) {
+        (Some(lon), Some(lat)) => (lon, lat),
+        _ => return Err(AppError::MissingCoordinates),
+    };
+
+    check_coords(lon, lat)?;
+    if !is_rounded(lon) || !is_rounded(lat) {
+        return Err(AppError::TooManyDecimalPlaces(DECIMAL_PLACES));
+    }
+
+    Ok(ApiLocation {
+        lon,
+        lat,
+        interpolation: interpolation.unwrap_or_default(),
+        name: state.location_name(lon, lat),
+        tz: state.timezone(lon, lat)?,
+    })
+}
+
+fn api_cache_headers(silam: &Silam, tz: &Tz) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let max_age = get_max_age(&silam.time_until_stale(), tz);
+    headers.insert(header::CACHE_CONTROL, cache_control(max_age));
+    headers
+}
+
+fn api_response(
+    request_headers: &HeaderMap,
+    params: Result<Query<ApiParams>, QueryRejection>,
+    state: &AppState,
+    silam: &Silam,
+) -> Result<Response, AppError> {
+    let Query(params) = params?;
+    let format = params
+        .format
+        .unwrap_or_else(|| Format::from_accept(request_headers));
+    let location = api_location(params.lon, params.lat, params.interpolation, state)?;
+    let window = ForecastWindow::from_params(
+        params.start.as_deref(),
+        params.end.as_deref(),
+        params.hours,
+        params.days,
+        &location.tz,
+        silam,
+    )?;
+    let pollen = silam.get_window(
+        &location.lon,
+        &location.lat,
+        location.interpolation,
+        &window,
+    )?;
+
+    let response = ApiResponse {
+        attribution: "Data from FMI SILAM and EAN".to_string(),
+        fetch_time: silam.fetch_time,
+        run_time: silam.run_time,
+        location: location.name,
+        pollen,
+    };
+    let mut headers = api_cache_headers(silam, &location.tz);
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(format.content_type()),
+    );
+
+    Ok(match format {
+        Format::Json => (headers, Json(response)).into_response(),
+        Format::Csv => (headers, response.to_csv(&location.tz)).into_response(),
+        Format::Ndjson => (headers, response.to_ndjson(&location.tz)).into_response(),
+    })
+}
+
+fn api_daily_response(
+    params: Result<Query<ApiParams>, QueryRejection>,
+    state: &AppState,
+    silam: &Silam,
+) -> Result<Response, AppError> {
+    let Query(params) = params?;
+    let location = api_location(params.lon, params.lat, params.interpolation, state)?;
+    // whole days by default, since a fixed number of hours cuts days short when clocks change
+    let days = match (&params.end, params.hours, params.days) {
+        (None, None, None) => Some(DAILY_DAYS),
+        _ => params.days,
+    };
+    let window = ForecastWindow::from_params(
+        params.start.as_deref(),
+        params.end.as_deref(),
+        params.hours,
+        days,
+        &location.tz,
+        silam,
+    )?;
+    let pollen = silam.get_window(
+        &location.lon,
+        &location.lat,
+        location.interpolation,
+        &window,
+    )?;
+
+    Ok((
+        api_cache_headers(silam, &location.tz),
+        Json(DailyResponse {
+            attribution: "Data from FMI SILAM and EAN".to_string(),
+            fetch_time: silam.fetch_time,
+            run_time: silam.run_time,
+            location: location.name,
+            timezone: location.tz.name().to_string(),
+            days: summarise_days(&pollen, &location.tz),
+        }),
+    )
+        .into_response())
+}
+
+/// Most locations a single `/api/batch` request may ask for.
+const MAX_BATCH_SIZE: usize = 100;
+
+#[derive(Deserialize)]
+pub struct BatchRequest {
+    locations: Vec<BatchLocation>,
+    interpolation: Option<Interpolation>,
+    start: Option<String>,
+    end: Option<String>,
+    hours: Option<usize>,
+    days: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct BatchLocation {
+    id: Option<String>,
+    lon: Option<f32>,
+    lat: Option<f32>,
+}
+
+#[derive(Serialize)]
+pub struct BatchResponse {
+    attribution: String,
+    fetch_time: DateTime<Utc>,
+    run_time: DateTime<Utc>,
+    results: Vec<BatchResult>,
+}
+
+/// Either `location` and `pollen`, or `error` when this location could not be forecast.
+#[derive(Serialize)]
+pub struct BatchResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    lon: Option<f32>,
+    lat: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pollen: Option<Vec<Pollen>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ApiError>,
+}
 
-        let max_age = get_max_age(&state.silam.read().unwrap().time_until_stale(), &tz);
-        let cache_control = format!("s-max-age={}, public, immutable, must-revalidate", max_age);
-        headers.insert(header::CACHE_CONTROL, cache_control.parse().unwrap());
+pub async fn api_batch(
+    State(state): State<Arc<AppState>>,
+    request: Result<Json<BatchRequest>, JsonRejection>,
+) -> Response {
+    let silam = state.silam.load_full();
+    with_cors(api_batch_response(request, &state, &silam))
+}
+
+fn api_batch_response(
+    request: Result<Json<BatchRequest>, JsonRejection>,
+    state: &AppState,
+    silam: &Silam,
+) -> Result<Response, AppError> {
+    let Json(request) = request?;
+    if request.locations.len() > MAX_BATCH_SIZE {
+        return Err(AppError::BatchTooLarge(MAX_BATCH_SIZE));
+    }
 
-        return (
-            headers,
-            Json(ApiResponse {
-                attribution: "Data from FMI SILAM and EAN".to_string(),
-                location: location_string,
+    let results = request
+        .locations
+        .into_iter()
+        .map(|item| {
+            let forecast = api_location(item.lon, item.lat, request.interpolation, state).and_then(
+                |location| {
+                    let window = ForecastWindow::from_params(
+                        request.start.as_deref(),
+                        request.end.as_deref(),
+                        request.hours,
+                        request.days,
+                        &location.tz,
+                        silam,
+                    )?;
+                    let pollen = silam.get_window(
+                        &location.lon,
+                        &location.lat,
+                        location.interpolation,
+                        &window,
+                    )?;
+                    Ok((location.name, pollen))
+                },
+            );
+            let (location, pollen, error) = match forecast {
+                Ok((location, pollen)) => (Some(location), Some(pollen), None),
+                Err(err) => (None, None, Some(err.to_api_error())),
+            };
+            BatchResult {
+                id: item.id,
+                lon: item.lon,
+                lat: item.lat,
+                location,
                 pollen,
-            }),
-        )
-            .into_response();
+                error,
+            }
+        })
+        .collect();
+
+    Ok(Json(BatchResponse {
+        attribution: "Data from FMI SILAM and EAN".to_string(),
+        fetch_time: silam.fetch_time,
+        run_time: silam.run_time,
+        results,
+    })
+    .into_response())
+}
+
+/// Level to alert on, `high` unless given.
+fn parse_threshold(threshold: Option<&str>) -> Result<PollenIndex, String> {
+    match threshold {
+        None => Ok(PollenIndex::High),
+        Some(name) => PollenIndex::from_name(name).ok_or_else(|| {
+            format!(
+                "threshold must be 1-5 or very low, low, moderate, high or very high, not {:?}",
+                name
+            )
+        }),
     }
+}
 
-    let cache_control = format!(
-        "s-max-age={}, public, immutable, must-revalidate",
-        &state.silam.read().unwrap().time_until_stale().num_seconds()
+#[derive(Deserialize)]
+pub struct CalendarParams {
+    lon: Option<f32>,
+    lat: Option<f32>,
+    interpolation: Option<Interpolation>,
+    threshold: Option<String>,
+}
+
+pub async fn calendar(
+    params: Result<Query<CalendarParams>, QueryRejection>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let silam = state.silam.load_full();
+    with_cors(calendar_response(params, &state, &silam))
+}
+
+fn calendar_response(
+    params: Result<Query<CalendarParams>, QueryRejection>,
+    state: &AppState,
+    silam: &Silam,
+) -> Result<Response, AppError> {
+    let Query(params) = params?;
+    let threshold = parse_threshold(params.threshold.as_deref()).map_err(AppError::InvalidQuery)?;
+    let location = api_location(params.lon, params.lat, params.interpolation, state)?;
+    // everything loaded, so recent periods stay on the calendar too
+    let window =
+        ForecastWindow::from_params(Some("first"), Some("last"), None, None, &location.tz, silam)?;
+    let pollen = silam.get_window(
+        &location.lon,
+        &location.lat,
+        location.interpolation,
+        &window,
+    )?;
+
+    let mut headers = api_cache_headers(silam, &location.tz);
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/calendar; charset=utf-8"),
     );
-    headers.insert(header::CACHE_CONTROL, cache_control.parse().unwrap());
 
-    (
-        StatusCode::BAD_REQUEST,
+    Ok((
+        headers,
+        high_pollen_calendar(
+            &pollen,
+            threshold,
+            &location.name,
+            (location.lon, location.lat),
+            &location.tz,
+            silam.fetch_time,
+        ),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct FeedParams {
+    lon: Option<f32>,
+    lat: Option<f32>,
+    interpolation: Option<Interpolation>,
+}
+
+pub async fn feed(
+    params: Result<Query<FeedParams>, QueryRejection>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let silam = state.silam.load_full();
+    with_cors(feed_response(params, &state, &silam))
+}
+
+fn feed_response(
+    params: Result<Query<FeedParams>, QueryRejection>,
+    state: &AppState,
+    silam: &Silam,
+) -> Result<Response, AppError> {
+    let Query(params) = params?;
+    let location = api_location(params.lon, params.lat, params.interpolation, state)?;
+    // the day the data arrived, so the entry reads the same until the next dataset replaces it
+    let date = silam.fetch_time.with_timezone(&location.tz).date_naive();
+    let window =
+        ForecastWindow::whole_days(date, 2, &location.tz).ok_or(AppError::DataUnavailable)?;
+    let pollen = silam.get_window(
+        &location.lon,
+        &location.lat,
+        location.interpolation,
+        &window,
+    )?;
+    let text = get_outlook_text(&summarise_days(&pollen, &location.tz), location.tz)?;
+
+    let mut headers = api_cache_headers(silam, &location.tz);
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/atom+xml; charset=utf-8"),
+    );
+
+    Ok((
         headers,
-        Json(ApiError {
-            msg: "?lat=&lon= query params missing".to_string(),
+        outlook_feed(
+            &state.site_url,
+            (location.lon, location.lat),
+            &Outlook {
+                location: &location.name,
+                date,
+                text: &text,
+                fetch_time: silam.fetch_time,
+                run_time: silam.run_time,
+            },
+        ),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct WebhookRequest {
+    url: String,
+    lon: Option<f32>,
+    lat: Option<f32>,
+    interpolation: Option<Interpolation>,
+    threshold: Option<String>,
+    species: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct WebhookResponse {
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret: Option<String>,
+    url: String,
+    lon: f32,
+    lat: f32,
+    interpolation: Interpolation,
+    threshold: PollenIndex,
+    species: Option<PollenType>,
+    created: DateTime<Utc>,
+}
+
+impl WebhookResponse {
+    /// The secret is only given out when the webhook is created.
+    fn new(webhook: Webhook, show_secret: bool) -> WebhookResponse {
+        WebhookResponse {
+            id: webhook.id,
+            secret: show_secret.then_some(webhook.secret),
+            url: webhook.url,
+            lon: webhook.lon,
+            lat: webhook.lat,
+            interpolation: webhook.interpolation,
+            threshold: webhook.threshold,
+            species: webhook.species,
+            created: webhook.created,
+        }
+    }
+}
+
+pub async fn create_webhook(
+    State(state): State<Arc<AppState>>,
+    request: Result<Json<WebhookRequest>, JsonRejection>,
+) -> Result<Response, AppError> {
+    let Json(request) = request?;
+    match reqwest::Url::parse(&request.url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+        _ => {
+            return Err(AppError::InvalidBody(format!(
+                "url must be an http or https URL, not {:?}",
+                request.url
+            )))
+        }
+    }
+    let threshold = parse_threshold(request.threshold.as_deref()).map_err(AppError::InvalidBody)?;
+    let species = match request.species.as_deref() {
+        None => None,
+        Some(name) => Some(
+            PollenType::from_name(name)
+                .ok_or_else(|| AppError::InvalidBody(format!("species not known: {:?}", name)))?,
+        ),
+    };
+    let location = api_location(request.lon, request.lat, request.interpolation, &state)?;
+    // refuse places that could never alert
+    state
+        .silam
+        .load()
+        .get_at_coords(&location.lon, &location.lat, location.interpolation)?;
+
+    let (webhook, created) = state
+        .webhooks
+        .register(NewWebhook {
+            url: request.url,
+            lon: location.lon,
+            lat: location.lat,
+            interpolation: location.interpolation,
+            threshold,
+            species,
+        })
+        .await?;
+
+    if created {
+        // alert on what is already forecast rather than waiting for the next refresh
+        notify_webhook(&state, &state.silam.load(), webhook.clone());
+        Ok((
+            StatusCode::CREATED,
+            Json(WebhookResponse::new(webhook, true)),
+        )
+            .into_response())
+    } else {
+        Ok(Json(WebhookResponse::new(webhook, false)).into_response())
+    }
+}
+
+/// The webhook, if the request holds its secret as a bearer token.
+fn authorized_webhook(
+    state: &AppState,
+    id: &str,
+    headers: &HeaderMap,
+) -> Result<Webhook, AppError> {
+    let webhook = state
+        .webhooks
+        .get(id)
+        .ok_or(AppError::SubscriptionNotFound)?;
+    match bearer_token(headers) {
+        Some(given) if secret::matches(&webhook.secret, given) => Ok(webhook),
+        _ => Err(AppError::Unauthorized),
+    }
+}
+
+pub async fn get_webhook(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let webhook = authorized_webhook(&state, &id, &headers)?;
+    Ok(Json(WebhookResponse::new(webhook, false)).into_response())
+}
+
+pub async fn delete_webhook(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    authorized_webhook(&state, &id, &headers)?;
+    state.webhooks.delete(&id)?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[derive(Deserialize)]
+pub struct PushKeys {
+    p256dh: String,
+    auth: String,
+}
+
+/// The browser's `PushSubscription`, as given by its `toJSON()`.
+#[derive(Deserialize)]
+pub struct BrowserSubscription {
+    endpoint: String,
+    keys: PushKeys,
+}
+
+#[derive(Deserialize)]
+pub struct PushRequest {
+    subscription: BrowserSubscription,
+    lon: Option<f32>,
+    lat: Option<f32>,
+    interpolation: Option<Interpolation>,
+    threshold: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct PushResponse {
+    id: String,
+    lon: f32,
+    lat: f32,
+    interpolation: Interpolation,
+    threshold: PollenIndex,
+}
+
+pub async fn push_subscribe(
+    State(state): State<Arc<AppState>>,
+    request: Result<Json<PushRequest>, JsonRejection>,
+) -> Result<Response, AppError> {
+    let Json(request) = request?;
+    match reqwest::Url::parse(&request.subscription.endpoint) {
+        Ok(url) if url.scheme() == "https" => {}
+        _ => {
+            return Err(AppError::InvalidBody(format!(
+                "endpoint must be an https URL, not {:?}",
+                request.subscription.endpoint
+            )))
+        }
+    }
+    let threshold = parse_threshold(request.threshold.as_deref()).map_err(AppError::InvalidBody)?;
+    let location = api_location(request.lon, request.lat, request.interpolation, &state)?;
+    state
+        .silam
+        .load()
+        .get_at_coords(&location.lon, &location.lat, location.interpolation)?;
+
+    let subscription = state
+        .push
+        .subscribe(NewPushSubscription {
+            endpoint: request.subscription.endpoint,
+            p256dh: request.subscription.keys.p256dh,
+            auth: request.subscription.keys.auth,
+            lon: location.lon,
+            lat: location.lat,
+            interpolation: location.interpolation,
+            threshold,
+        })
+        .await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(PushResponse {
+            id: subscription.id,
+            lon: subscription.lon,
+            lat: subscription.lat,
+            interpolation: subscription.interpolation,
+            threshold: subscription.threshold,
         }),
     )
-        .into_response()
+        .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct PushUnsubscribeRequest {
+    endpoint: String,
+    lon: Option<f32>,
+    lat: Option<f32>,
+    interpolation: Option<Interpolation>,
+}
+
+pub async fn push_unsubscribe(
+    State(state): State<Arc<AppState>>,
+    request: Result<Json<PushUnsubscribeRequest>, JsonRejection>,
+) -> Result<Response, AppError> {
+    let Json(request) = request?;
+    let location = match (request.lon, request.lat) {
+        (None, None) => None,
+        (lon, lat) => {
+            let location = api_location(lon, lat, request.interpolation, &state)?;
+            Some((location.lon, location.lat, location.interpolation))
+        }
+    };
+    state.push.unsubscribe(&request.endpoint, location)?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[derive(Deserialize)]
+pub struct EmailLocationParams {
+    lon: Option<f32>,
+    lat: Option<f32>,
+    interpolation: Option<Interpolation>,
+}
+
+#[derive(Deserialize)]
+pub struct EmailSubscribeForm {
+    email: String,
+    schedule: Schedule,
+    threshold: Option<String>,
+}
+
+pub async fn email_subscribe(
+    State(state): State<Arc<AppState>>,
+    params: Result<Query<EmailLocationParams>, QueryRejection>,
+    form: Result<Form<EmailSubscribeForm>, FormRejection>,
+) -> Response {
+    let silam = state.silam.load_full();
+    match email_subscribe_response(&state, &silam, params, form).await {
+        Ok(response) => response,
+        Err(err) => err.into_html(&silam).into_response(),
+    }
 }
 
-pub async fn emf_phone(State(state): State<Arc<AppState>>) -> Response {
+async fn email_subscribe_response(
+    state: &AppState,
+    silam: &Silam,
+    params: Result<Query<EmailLocationParams>, QueryRejection>,
+    form: Result<Form<EmailSubscribeForm>, FormRejection>,
+) -> Result<Response, AppError> {
+    let Query(params) = params?;
+    let Form(form) = form?;
+    let email = form.email.trim().to_string();
+    if email.parse::<lettre::Address>().is_err() {
+        return Err(AppError::InvalidBody(format!(
+            "not a valid email address: {:?}",
+            email
+        )));
+    }
+    let threshold = parse_threshold(form.threshold.as_deref()).map_err(AppError::InvalidBody)?;
+    let location = api_location(params.lon, params.lat, params.interpolation, state)?;
+    silam.get_at_coords(&location.lon, &location.lat, location.interpolation)?;
+
+    let (subscription, send) = state.email.subscribe(NewEmailSubscription {
+        email,
+        lon: location.lon,
+        lat: location.lat,
+        interpolation: location.interpolation,
+        schedule: form.schedule,
+        threshold,
+    })?;
+    // the page is the same either way, so it doesn't reveal who is subscribed or whether an
+    // email was held back
+    if send {
+        send_confirmation(state, &subscription).await?;
+    }
+
+    Ok(page(
+        true,
+        silam.fetch_time,
+        silam.run_time,
+        email_sent(&subscription.email),
+    )
+    .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct EmailLinkParams {
+    id: String,
+    token: String,
+}
+
+pub async fn email_confirm(
+    State(state): State<Arc<AppState>>,
+    params: Result<Query<EmailLinkParams>, QueryRejection>,
+) -> Response {
+    let silam = state.silam.load_full();
+    let result = params.map_err(AppError::from).and_then(|Query(params)| {
+        let subscription = state.email.confirm(&params.id, &params.token)?;
+        let location = state.location_name(subscription.lon, subscription.lat);
+        let query = forecast_query(
+            subscription.lon,
+            subscription.lat,
+            subscription.interpolation,
+        );
+        Ok(page(
+            true,
+            silam.fetch_time,
+            silam.run_time,
+            email_confirmed(&location, &query),
+        )
+        .into_response())
+    });
+    result.unwrap_or_else(|err| err.into_html(&silam).into_response())
+}
+
+/// Asks before unsubscribing, so link scanners following the link don't unsubscribe anyone.
+pub async fn email_unsubscribe_page(
+    State(state): State<Arc<AppState>>,
+    params: Result<Query<EmailLinkParams>, QueryRejection>,
+) -> Response {
+    let silam = state.silam.load_full();
+    match params {
+        Ok(Query(params)) => page(
+            true,
+            silam.fetch_time,
+            silam.run_time,
+            unsubscribe_form(&params.id, &params.token),
+        )
+        .into_response(),
+        Err(err) => AppError::from(err).into_html(&silam).into_response(),
+    }
+}
+
+/// Unsubscribes from the page's button or a mail client's one-click unsubscribe.
+pub async fn email_unsubscribe(
+    State(state): State<Arc<AppState>>,
+    params: Result<Query<EmailLinkParams>, QueryRejection>,
+) -> Response {
+    let silam = state.silam.load_full();
+    let result = params.map_err(AppError::from).and_then(|Query(params)| {
+        state.email.unsubscribe(&params.id, &params.token)?;
+        Ok(page(true, silam.fetch_time, silam.run_time, unsubscribed()).into_response())
+    });
+    result.unwrap_or_else(|err| err.into_html(&silam).into_response())
+}
+
+pub async fn emf_phone(State(state): State<Arc<AppState>>) -> Result<Response, AppError> {
     let lon: f32 = -2.38;
     let lat: f32 = 52.04;
+    let silam = state.silam.load_full();
 
-    let tz: Tz = state
-        .finder
-        .get_tz_name(lon.into(), lat.into())
-        .parse()
-        .unwrap();
-
-    let now_index: usize = (Local::now().with_timezone(&tz).to_utc()
-        - state.silam.read().unwrap().start_time)
-        .num_hours()
-        .try_into()
-        .unwrap();
-
-    let start_index: usize = (Local::now()
-        .with_timezone(&tz)
-        .with_time(NaiveTime::from_hms_opt(0, 0, 0).unwrap())
-        .unwrap()
-        .to_utc()
-        - state.silam.read().unwrap().start_time)
-        .num_hours()
-        .try_into()
-        .unwrap();
-    let end_index = start_index + 72;
-    let pollen = state
-        .silam
-        .read()
-        .unwrap()
-        .get_at_coords(&lon, &lat)
-        .drain(start_index..end_index)
-        .collect();
+    let tz = state.timezone(lon, lat)?;
+    let window = ForecastWindow::from_params(None, None, None, Some(3), &tz, &silam)?;
+    let pollen = silam.get_window(&lon, &lat, Interpolation::Nearest, &window)?;
+    let now = Utc::now();
+    let pollen_now = pollen
+        .iter()
+        .rev()
+        .find(|pollen| pollen.time <= now)
+        .ok_or(AppError::DataUnavailable)?;
 
-    let text = get_phone_text(&pollen, now_index, tz);
+    let text = get_phone_text(pollen_now, &summarise_days(&pollen, &tz), tz)?;
 
-    return Json(json!([
+    Ok(Json(json!([
         {
             "verb": "say",
             "text": text
@@ -297,5 +1149,120 @@ pub async fn emf_phone(State(state): State<Arc<AppState>>) -> Response {
             "verb": "hangup"
         }
     ]))
-    .into_response();
+    .into_response())
+}
+
+/// What `/health` and `/ready` report. The server doesn't start until data is loaded, so this
+/// is always about a loaded dataset.
+#[derive(Serialize)]
+pub struct ServiceStatus {
+    fetch_time: DateTime<Utc>,
+    run_time: DateTime<Utc>,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    next_run_expected_at: DateTime<Utc>,
+    stale: bool,
+    seconds_until_stale: i64,
+    /// Age of the model run.
+    data_age_seconds: i64,
+    max_data_age_seconds: i64,
+    refresh: RefreshStatus,
+    geocoder: Vec<GeocoderStatus>,
+}
+
+impl ServiceStatus {
+    fn new(state: &AppState) -> ServiceStatus {
+        let silam = state.silam.load_full();
+        ServiceStatus {
+            fetch_time: silam.fetch_time,
+            run_time: silam.run_time,
+            start_time: silam.start_time,
+            end_time: silam.end_time(),
+            next_run_expected_at: silam.next_run_expected_at(),
+            stale: silam.is_stale(),
+            seconds_until_stale: silam.time_until_stale().num_seconds(),
+            data_age_seconds: (Utc::now() - silam.run_time).num_seconds(),
+            max_data_age_seconds: state.max_data_age.num_seconds(),
+            refresh: state.refresh_status.read().unwrap().clone(),
+            geocoder: state.geocoder.status(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct HealthResponse {
+    healthy: bool,
+    #[serde(flatten)]
+    status: ServiceStatus,
+}
+
+pub async fn health(State(state): State<Arc<AppState>>) -> Response {
+    let status = ServiceStatus::new(&state);
+    // stale data is only a problem once refreshing it has failed
+    let healthy = !status.stale || status.refresh.consecutive_failures == 0;
+
+    (
+        if healthy {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        },
+        Json(HealthResponse { healthy, status }),
+    )
+        .into_response()
+}
+
+#[derive(Serialize)]
+pub struct ReadyResponse {
+    ready: bool,
+    #[serde(flatten)]
+    status: ServiceStatus,
+}
+
+/// Fails once the data is older than `max_data_age`, for load balancers to stop sending
+/// traffic to an instance that can't refresh.
+pub async fn ready(State(state): State<Arc<AppState>>) -> Response {
+    let status = ServiceStatus::new(&state);
+    let ready = status.data_age_seconds <= status.max_data_age_seconds;
+
+    (
+        if ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        },
+        Json(ReadyResponse { ready, status }),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+pub struct PurgeParams {
+    /// Only forget this search, rather than all of them.
+    q: Option<String>,
+}
+
+pub async fn purge_geocoding_cache(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    params: Result<Query<PurgeParams>, QueryRejection>,
+) -> Result<Response, AppError> {
+    check_admin(&state, &headers)?;
+    let Query(params) = params?;
+    let purged = state.geocoder.purge_cache(params.q.as_deref()).await?;
+    Ok(Json(json!({ "purged": purged })).into_response())
+}
+
+fn check_admin(state: &AppState, headers: &HeaderMap) -> Result<(), AppError> {
+    match (&state.admin_token, bearer_token(headers)) {
+        (Some(expected), Some(given)) if secret::matches(expected, given) => Ok(()),
+        _ => Err(AppError::Unauthorized),
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
 }
@@ -2,9 +2,17 @@ use chrono::{DateTime, Locale, Utc};
 use chrono_tz::Tz;
 use maud::{html, Markup, DOCTYPE};
 
-use crate::silam::Pollen;
+use crate::{
+    geocode::GeocodedPlace,
+    silam::{Interpolation, Pollen, PollenType},
+};
 
-pub fn page(back_enabled: bool, fetched_at: DateTime<Utc>, content: Markup) -> Markup {
+pub fn page(
+    back_enabled: bool,
+    fetched_at: DateTime<Utc>,
+    run_time: DateTime<Utc>,
+    content: Markup,
+) -> Markup {
     html! {
         (DOCTYPE)
         html lang="en" {
@@ -30,6 +38,8 @@ pub fn page(back_enabled: bool, fetched_at: DateTime<Utc>, content: Markup) -> M
                         small {
                             "Data was fetched at: "
                             (fetched_at)
+                            " from the model run at: "
+                            (run_time)
                             ". For enquiries contact webmaster at this domain."
                         }
                     }
@@ -65,15 +75,117 @@ pub fn home() -> Markup {
     }
 }
 
-pub fn forecast(pollen: &Vec<Pollen>, location: &String, timezone: &Tz, locale: &Locale) -> Markup {
+pub fn outside_coverage(location: &String) -> Markup {
     html! {
         h2 { (location) }
         p {
-            "Pollen count: 1 (low) - 5 (high). Main pollen source in brackets. Data from "
+            "Sorry, there is no pollen forecast for this location. "
             a href="https://silam.fmi.fi/" { "FMI SILAM" }
-            " and "
-            a href="https://www.polleninfo.org/" { "EAN" }
-            "."
+            " only models pollen over Europe and the areas around it."
+        }
+        p { a href="/" { "Try another location" } }
+    }
+}
+
+/// Places a search matched, each with a link to its forecast and whether it has one.
+pub fn search_results(loc: &str, places: &[(String, &GeocodedPlace, bool)]) -> Markup {
+    html! {
+        h2 { "Places matching “" (loc) "”" }
+        ul class="results" {
+            @for (href, place, covered) in places {
+                li {
+                    a href=(href) { (place.name) }
+                    @if let Some(country) = &place.country {
+                        " " small { "(" (country) ")" }
+                    }
+                    br;
+                    @if *covered {
+                        small { "✓ Forecast available" }
+                    } @else {
+                        small class="uncovered" { "✗ Outside forecast area" }
+                    }
+                }
+            }
+        }
+        p { a href="/" { "Search again" } }
+    }
+}
+
+pub fn search_busy(loc: &str) -> Markup {
+    html! {
+        h2 { "Search is busy" }
+        p { "Too many people are searching right now. Please try again in a few seconds." }
+        form action="" method="GET" {
+            input type="hidden" name="loc" value=(loc);
+            input type="submit" value="Try again";
+        }
+    }
+}
+
+pub fn error_page(message: &str) -> Markup {
+    html! {
+        h2 { "Something went wrong" }
+        p { (message) }
+        p { a href="/" { "Try another location" } }
+    }
+}
+
+/// Query string for the forecast page at these coordinates.
+pub fn forecast_query(lon: f32, lat: f32, interpolation: Interpolation) -> String {
+    match interpolation {
+        Interpolation::Nearest => format!("lat={}&lon={}", lat, lon),
+        Interpolation::Bilinear => format!("lat={}&lon={}&interpolation=bilinear", lat, lon),
+        Interpolation::Max => format!("lat={}&lon={}&interpolation=max", lat, lon),
+    }
+}
+
+pub fn forecast(
+    pollen: &Vec<Pollen>,
+    location: &String,
+    timezone: &Tz,
+    locale: &Locale,
+    (lon, lat): (f32, f32),
+    species: Option<PollenType>,
+    interpolation: Interpolation,
+) -> Markup {
+    let query = forecast_query(lon, lat, interpolation);
+
+    html! {
+        h2 { (location) }
+        @if let Some(species) = species {
+            p {
+                (species) " pollen concentration in grains/m³. Data from "
+                a href="https://silam.fmi.fi/" { "FMI SILAM" }
+                " and "
+                a href="https://www.polleninfo.org/" { "EAN" }
+                "."
+            }
+        } @else {
+            p {
+                "Pollen count: 1 (low) - 5 (high). Main pollen source in brackets. Data from "
+                a href="https://silam.fmi.fi/" { "FMI SILAM" }
+                " and "
+                a href="https://www.polleninfo.org/" { "EAN" }
+                "."
+            }
+        }
+        p {
+            small {
+                "Show: "
+                a href={ "/?" (query) } { "All" }
+                @for s in PollenType::SPECIES {
+                    " · "
+                    a href={ "/?" (query) "&species=" (s.to_spoken()) } { (s) }
+                }
+            }
+        }
+        p {
+            small {
+                a href={ "/calendar.ics?" (query) } { "Subscribe to high pollen periods" }
+                " (iCalendar) · "
+                a href={ "/feed.atom?" (query) } { "Daily outlook feed" }
+                " (Atom)"
+            }
         }
         table {
             tr {
@@ -85,11 +197,84 @@ pub fn forecast(pollen: &Vec<Pollen>, location: &String, timezone: &Tz, locale:
             @for n in 0..24 {
                 tr {
                     td { (pollen[n].time.with_timezone(timezone).format_localized("%R", *locale)) }
-                    td class={ "level-" (pollen[n].pollen_index) } { (pollen[n].pollen_index) " (" (pollen[n].pollen_index_source) ")" }
-                    td class={ "level-" (pollen[n + 24].pollen_index) } { (pollen[n + 24].pollen_index) " (" (pollen[n + 24].pollen_index_source) ")" }
-                    td class={ "level-" (pollen[n + 48].pollen_index) } { (pollen[n + 48].pollen_index) " (" (pollen[n + 48].pollen_index_source) ")" }
+                    (forecast_cell(&pollen[n], species))
+                    (forecast_cell(&pollen[n + 24], species))
+                    (forecast_cell(&pollen[n + 48], species))
                 }
             }
         }
     }
 }
+
+/// Button to get browser notifications, shown by `script.js` where Web Push is supported.
+pub fn push_form(forecast_query: &str, public_key: &str) -> Markup {
+    html! {
+        form id="push-form" hidden data-key=(public_key) data-query=(forecast_query) {
+            input type="button" value="Notify me of high pollen" id="push";
+        }
+    }
+}
+
+pub fn email_form(forecast_query: &str) -> Markup {
+    html! {
+        h3 { "Get emails" }
+        form action={ "/email/subscribe?" (forecast_query) } method="POST" {
+            label for="email" { "Email" }
+            input type="email" name="email" id="email" required;
+            label for="schedule" { "Send" }
+            select name="schedule" id="schedule" {
+                option value="daily" { "Every morning" }
+                option value="threshold" { "When pollen will be high" }
+            }
+            input type="submit" value="Subscribe";
+        }
+    }
+}
+
+pub fn email_sent(email: &str) -> Markup {
+    html! {
+        h2 { "Check your inbox" }
+        p {
+            "If " (email) " isn't already subscribed, an email is on its way with a link to "
+            "confirm. Nothing else is sent until it is followed."
+        }
+        p { a href="/" { "Back to the start" } }
+    }
+}
+
+pub fn email_confirmed(location: &str, forecast_query: &str) -> Markup {
+    html! {
+        h2 { "Subscribed" }
+        p {
+            "You will get pollen emails for " (location) ". Each one has a link to unsubscribe."
+        }
+        p { a href={ "/?" (forecast_query) } { "See the forecast" } }
+    }
+}
+
+pub fn unsubscribe_form(id: &str, token: &str) -> Markup {
+    html! {
+        h2 { "Unsubscribe" }
+        form action={ "/email/unsubscribe?id=" (id) "&token=" (token) } method="POST" {
+            input type="submit" value="Stop sending me emails";
+        }
+    }
+}
+
+pub fn unsubscribed() -> Markup {
+    html! {
+        h2 { "Unsubscribed" }
+        p { "No more emails will be sent for this subscription." }
+        p { a href="/" { "Back to the start" } }
+    }
+}
+
+fn forecast_cell(pollen: &Pollen, species: Option<PollenType>) -> Markup {
+    html! {
+        @if let Some(species) = species {
+            td { (format!("{:.0}", pollen.concentrations.get(species))) }
+        } @else {
+            td class={ "level-" (pollen.pollen_index) } { (pollen.pollen_index) " (" (pollen.pollen_index_source) ")" }
+        }
+    }
+}
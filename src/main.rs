@@ -1,65 +1,222 @@
+use arc_swap::ArcSwap;
 use axum::{
-    routing::{get, post},
+    middleware,
+    routing::{delete, get, post},
     Router,
 };
+use chrono::Duration;
+use chrono_tz::Tz;
 use handlers::emf_phone;
-use nominatim::{Client, IdentificationMethod};
-use reverse_geocoder::ReverseGeocoder;
+use reverse_geocoder::{Record, ReverseGeocoder};
 use shuttle_runtime::SecretStore;
-use std::sync::{Arc, RwLock};
-use tokio::time;
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+use tokio::sync::watch;
 use tower_http::services::ServeDir;
 use tzf_rs::DefaultFinder;
 
+mod calendar;
+mod daily;
+mod email;
+mod error;
+mod feed;
+mod format;
+mod geocache;
+mod geocode;
 mod handlers;
 mod html;
+mod metrics;
+mod outbound;
 mod phone;
+mod push;
+mod refresh;
+mod secret;
 mod silam;
+mod source;
+mod store;
+mod webhooks;
+mod window;
 
 use crate::{
-    handlers::{api, index},
+    email::{send_emails, EmailStore},
+    error::AppError,
+    geocache::GeocodeCache,
+    geocode::Geocoder,
+    handlers::{
+        api, api_batch, api_daily, api_events, calendar, create_webhook, delete_webhook,
+        email_confirm, email_subscribe, email_unsubscribe, email_unsubscribe_page, feed,
+        get_webhook, health, index, purge_geocoding_cache, push_subscribe, push_unsubscribe, ready,
+    },
+    metrics::{metrics, track_requests},
+    push::PushStore,
+    refresh::{silam_refetch_if_stale, RefreshStatus},
     silam::Silam,
+    source::SilamSource,
+    webhooks::WebhookStore,
 };
 
+/// Runs are daily and published a few hours late, so this allows for about one missed run.
+const DEFAULT_MAX_DATA_AGE_HOURS: i64 = 48;
+/// Place names rarely move, so searches are cached for a long time.
+const DEFAULT_GEOCODE_CACHE_DAYS: i64 = 30;
+
 pub struct AppState {
     finder: DefaultFinder,
-    nominatim: Client,
+    geocoder: Geocoder,
     reverse_geocoder: ReverseGeocoder,
-    silam: RwLock<Silam>,
-    silam_email: Option<String>,
+    silam: ArcSwap<Silam>,
+    silam_source: SilamSource,
+    refresh_status: RwLock<RefreshStatus>,
+    /// Age of the model run past which `/ready` fails.
+    max_data_age: Duration,
+    /// Notified each time new SILAM data is installed.
+    silam_installed: watch::Sender<()>,
+    site_url: String,
+    webhooks: WebhookStore,
+    email: EmailStore,
+    push: PushStore,
+    /// Bearer token for `/admin` routes, which are refused if it is not set.
+    admin_token: Option<String>,
+}
+
+impl AppState {
+    pub fn timezone(&self, lon: f32, lat: f32) -> Result<Tz, AppError> {
+        let name = self.finder.get_tz_name(lon.into(), lat.into());
+        name.parse()
+            .map_err(|_| AppError::UnknownTimezone(name.to_string()))
+    }
+
+    pub fn nearest_place(&self, lon: f32, lat: f32) -> &Record {
+        self.reverse_geocoder
+            .search((lat.into(), lon.into()))
+            .record
+    }
+
+    /// Name of the nearest place, for labelling forecasts.
+    pub fn location_name(&self, lon: f32, lat: f32) -> String {
+        let location = self.nearest_place(lon, lat);
+        format!(
+            "{}, {}, {}, {}",
+            location.name, location.admin1, location.admin2, location.cc,
+        )
+    }
 }
 
 #[shuttle_runtime::main]
 async fn main(#[shuttle_runtime::Secrets] secrets: SecretStore) -> shuttle_axum::ShuttleAxum {
-    let silam_email = secrets.get("SILAM_EMAIL");
+    let silam_source = SilamSource::from_config(
+        secrets.get("SILAM_SOURCE"),
+        secrets.get("SILAM_EMAIL"),
+        secrets.get("SILAM_CACHE_DIR"),
+    );
+    let site_url = secrets
+        .get("SITE_URL")
+        .unwrap_or_else(|| "https://pollen.party".to_string())
+        .trim_end_matches('/')
+        .to_string();
+    let max_data_age = Duration::hours(
+        secrets
+            .get("MAX_DATA_AGE_HOURS")
+            .map(|hours| {
+                hours
+                    .parse()
+                    .expect("MAX_DATA_AGE_HOURS must be a whole number")
+            })
+            .unwrap_or(DEFAULT_MAX_DATA_AGE_HOURS),
+    );
+    let data_dir = secrets.get("DATA_DIR").map(PathBuf::from);
+    // only for testing against receivers on this machine or network
+    let allow_local_targets = secrets
+        .get("ALLOW_LOCAL_TARGETS")
+        .is_some_and(|allow| allow == "true");
+    let webhooks = WebhookStore::open(data_dir.as_deref(), allow_local_targets).unwrap();
+    let email = EmailStore::open(
+        data_dir.as_deref(),
+        &site_url,
+        secrets.get("SMTP_URL").as_deref(),
+        secrets.get("EMAIL_FROM").as_deref(),
+        secrets.get("EMAIL_SIGNING_KEY").as_deref(),
+    )
+    .unwrap();
+    let push = PushStore::open(
+        data_dir.as_deref(),
+        secrets.get("VAPID_PRIVATE_KEY").as_deref(),
+        secrets
+            .get("VAPID_SUBJECT")
+            .unwrap_or_else(|| site_url.clone()),
+        allow_local_targets,
+    )
+    .unwrap();
+    let geocode_cache = GeocodeCache::open(
+        data_dir.as_deref(),
+        Duration::days(
+            secrets
+                .get("GEOCODE_CACHE_DAYS")
+                .map(|days| {
+                    days.parse()
+                        .expect("GEOCODE_CACHE_DAYS must be a whole number")
+                })
+                .unwrap_or(DEFAULT_GEOCODE_CACHE_DAYS),
+        ),
+    )
+    .unwrap();
+    let geocoder =
+        Geocoder::from_config(secrets.get("GEOCODERS").as_deref(), geocode_cache).unwrap();
+    // start from the last good download if there is one, the refresher replaces it once stale
+    let silam = match silam_source.load_cached() {
+        Some(silam) => silam,
+        None => silam_source.load().await.unwrap(),
+    };
 
     let state = Arc::new(AppState {
         finder: DefaultFinder::new(),
-        nominatim: Client::new(IdentificationMethod::from_user_agent("pollen.party")),
+        geocoder,
         reverse_geocoder: ReverseGeocoder::new(),
-        silam: RwLock::new(Silam::fetch(&silam_email).await.unwrap()),
-        silam_email,
+        silam: ArcSwap::from_pointee(silam),
+        silam_source,
+        refresh_status: RwLock::new(RefreshStatus::default()),
+        max_data_age,
+        silam_installed: watch::channel(()).0,
+        site_url,
+        webhooks,
+        email,
+        push,
+        admin_token: secrets.get("ADMIN_TOKEN"),
     });
 
     let router = Router::new()
         .route("/", get(index))
         .route("/api", get(api))
+        .route("/api/daily", get(api_daily))
+        .route("/api/batch", post(api_batch))
+        .route("/api/events", get(api_events))
+        .route("/calendar.ics", get(calendar))
+        .route("/feed.atom", get(feed))
+        .route("/api/webhooks", post(create_webhook))
+        .route(
+            "/api/webhooks/{id}",
+            get(get_webhook).delete(delete_webhook),
+        )
+        .route("/api/push", post(push_subscribe).delete(push_unsubscribe))
+        .route("/email/subscribe", post(email_subscribe))
+        .route("/email/confirm", get(email_confirm))
+        .route(
+            "/email/unsubscribe",
+            get(email_unsubscribe_page).post(email_unsubscribe),
+        )
         .route("/emfphone", post(emf_phone))
+        .route("/health", get(health))
+        .route("/ready", get(ready))
+        .route("/metrics", get(metrics))
+        .route("/admin/geocoding-cache", delete(purge_geocoding_cache))
+        .layer(middleware::from_fn(track_requests))
         .with_state(Arc::clone(&state))
         .fallback_service(ServeDir::new("assets"));
 
     tokio::spawn(silam_refetch_if_stale(Arc::clone(&state)));
+    tokio::spawn(send_emails(Arc::clone(&state)));
 
     Ok(router.into())
 }
-
-async fn silam_refetch_if_stale(state: Arc<AppState>) -> () {
-    let mut interval = time::interval(std::time::Duration::from_secs(10));
-    loop {
-        interval.tick().await;
-        if state.silam.read().unwrap().is_stale() {
-            let silam = Silam::fetch(&state.silam_email).await.unwrap();
-            *state.silam.write().unwrap() = silam;
-        }
-    }
-}
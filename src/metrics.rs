@@ -0,0 +1,363 @@
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::HeaderValue,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use reqwest::header;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::AppState;
+
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+/// Downloads take minutes, so get their own buckets.
+const FETCH_BUCKETS: &[f64] = &[1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0];
+
+/// Counters and histograms for the whole process, rendered for Prometheus by `/metrics`.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+#[derive(Default)]
+pub struct Metrics {
+    registry: Mutex<Registry>,
+}
+
+#[derive(Default)]
+struct Registry {
+    requests: BTreeMap<(String, u16), u64>,
+    request_durations: BTreeMap<String, Histogram>,
+    geocoder_requests: BTreeMap<(&'static str, &'static str), u64>,
+    geocoder_durations: BTreeMap<&'static str, Histogram>,
+    cache_requests: BTreeMap<(&'static str, &'static str), u64>,
+    refreshes: BTreeMap<&'static str, u64>,
+    fetch_durations: Option<Histogram>,
+    fetched_bytes: u64,
+    last_fetch_bytes: u64,
+}
+
+struct Histogram {
+    buckets: &'static [f64],
+    /// Observations per bucket, not yet cumulative.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Histogram {
+        Histogram {
+            buckets,
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = self.buckets.iter().position(|le| seconds <= *le) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (le, count) in self.buckets.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, separator, le, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, separator, self.count
+        );
+        let braced = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, braced, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, braced, self.count);
+    }
+}
+
+impl Metrics {
+    pub fn record_request(&self, route: &str, status: u16, duration: Duration) {
+        let mut registry = self.registry.lock().unwrap();
+        *registry
+            .requests
+            .entry((route.to_string(), status))
+            .or_default() += 1;
+        registry
+            .request_durations
+            .entry(route.to_string())
+            .or_insert_with(|| Histogram::new(LATENCY_BUCKETS))
+            .observe(duration);
+    }
+
+    pub fn record_geocoder(&self, provider: &'static str, success: bool, duration: Duration) {
+        let mut registry = self.registry.lock().unwrap();
+        let result = if success { "success" } else { "failure" };
+        *registry
+            .geocoder_requests
+            .entry((provider, result))
+            .or_default() += 1;
+        registry
+            .geocoder_durations
+            .entry(provider)
+            .or_insert_with(|| Histogram::new(LATENCY_BUCKETS))
+            .observe(duration);
+    }
+
+    /// A search turned away from `provider` because too many were waiting.
+    pub fn record_geocoder_busy(&self, provider: &'static str) {
+        *self
+            .registry
+            .lock()
+            .unwrap()
+            .geocoder_requests
+            .entry((provider, "busy"))
+            .or_default() += 1;
+    }
+
+    pub fn record_cache(&self, cache: &'static str, hit: bool) {
+        let result = if hit { "hit" } else { "miss" };
+        *self
+            .registry
+            .lock()
+            .unwrap()
+            .cache_requests
+            .entry((cache, result))
+            .or_default() += 1;
+    }
+
+    pub fn record_refresh(&self, success: bool) {
+        let result = if success { "success" } else { "failure" };
+        *self
+            .registry
+            .lock()
+            .unwrap()
+            .refreshes
+            .entry(result)
+            .or_default() += 1;
+    }
+
+    pub fn record_fetch(&self, duration: Duration, bytes: usize) {
+        let mut registry = self.registry.lock().unwrap();
+        registry
+            .fetch_durations
+            .get_or_insert_with(|| Histogram::new(FETCH_BUCKETS))
+            .observe(duration);
+        registry.fetched_bytes += bytes as u64;
+        registry.last_fetch_bytes = bytes as u64;
+    }
+
+    /// Prometheus text exposition of everything recorded, plus gauges read from `state`.
+    fn render(&self, state: &AppState) -> String {
+        let registry = self.registry.lock().unwrap();
+        // writing to a String cannot fail
+        let mut out = String::new();
+
+        describe(
+            &mut out,
+            "pollen_http_requests_total",
+            "counter",
+            "HTTP requests by route and status.",
+        );
+        for ((route, status), count) in &registry.requests {
+            let _ = writeln!(
+                out,
+                "pollen_http_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+                escape_label(route),
+                status,
+                count
+            );
+        }
+        describe(
+            &mut out,
+            "pollen_http_request_duration_seconds",
+            "histogram",
+            "HTTP request latency by route.",
+        );
+        for (route, histogram) in &registry.request_durations {
+            histogram.write(
+                &mut out,
+                "pollen_http_request_duration_seconds",
+                &format!("route=\"{}\"", escape_label(route)),
+            );
+        }
+
+        describe(
+            &mut out,
+            "pollen_geocoder_requests_total",
+            "counter",
+            "Location searches by provider and result, busy ones were turned away without reaching the provider.",
+        );
+        for ((provider, result), count) in &registry.geocoder_requests {
+            let _ = writeln!(
+                out,
+                "pollen_geocoder_requests_total{{provider=\"{}\",result=\"{}\"}} {}",
+                provider, result, count
+            );
+        }
+        describe(
+            &mut out,
+            "pollen_geocoder_request_duration_seconds",
+            "histogram",
+            "Location search latency by provider.",
+        );
+        for (provider, histogram) in &registry.geocoder_durations {
+            histogram.write(
+                &mut out,
+                "pollen_geocoder_request_duration_seconds",
+                &format!("provider=\"{}\"", provider),
+            );
+        }
+
+        describe(
+            &mut out,
+            "pollen_cache_requests_total",
+            "counter",
+            "Cache lookups by cache and result, hit ratio is hits over the total.",
+        );
+        for ((cache, result), count) in &registry.cache_requests {
+            let _ = writeln!(
+                out,
+                "pollen_cache_requests_total{{cache=\"{}\",result=\"{}\"}} {}",
+                cache, result, count
+            );
+        }
+
+        describe(
+            &mut out,
+            "pollen_refreshes_total",
+            "counter",
+            "Attempts to refresh SILAM data by result.",
+        );
+        for (result, count) in &registry.refreshes {
+            let _ = writeln!(
+                out,
+                "pollen_refreshes_total{{result=\"{}\"}} {}",
+                result, count
+            );
+        }
+        describe(
+            &mut out,
+            "pollen_silam_fetch_duration_seconds",
+            "histogram",
+            "Time to download and parse SILAM data.",
+        );
+        if let Some(histogram) = &registry.fetch_durations {
+            histogram.write(&mut out, "pollen_silam_fetch_duration_seconds", "");
+        }
+        describe(
+            &mut out,
+            "pollen_silam_fetched_bytes_total",
+            "counter",
+            "Bytes of SILAM data downloaded.",
+        );
+        let _ = writeln!(
+            out,
+            "pollen_silam_fetched_bytes_total {}",
+            registry.fetched_bytes
+        );
+        describe(
+            &mut out,
+            "pollen_silam_last_fetch_bytes",
+            "gauge",
+            "Size of the last SILAM download.",
+        );
+        let _ = writeln!(
+            out,
+            "pollen_silam_last_fetch_bytes {}",
+            registry.last_fetch_bytes
+        );
+
+        let silam = state.silam.load();
+        let now = Utc::now();
+        for (name, help, value) in [
+            (
+                "pollen_silam_grid_cells",
+                "Cells in the loaded SILAM grid.",
+                silam.grid_cells() as f64,
+            ),
+            (
+                "pollen_silam_grid_hours",
+                "Hourly steps in the loaded SILAM data.",
+                (silam.end_time() - silam.start_time).num_hours() as f64,
+            ),
+            (
+                "pollen_silam_data_age_seconds",
+                "Age of the model run of the loaded data.",
+                (now - silam.run_time).num_seconds() as f64,
+            ),
+            (
+                "pollen_silam_fetch_age_seconds",
+                "Time since the loaded data was downloaded.",
+                (now - silam.fetch_time).num_seconds() as f64,
+            ),
+            (
+                "pollen_silam_stale",
+                "Whether a newer model run is expected to be available.",
+                if silam.is_stale() { 1.0 } else { 0.0 },
+            ),
+        ] {
+            describe(&mut out, name, "gauge", help);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        out
+    }
+}
+
+fn describe(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Records the count and latency of every request to a route. Routes are labelled by their
+/// pattern so ids in paths don't make a series each.
+pub async fn track_requests(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string());
+    let started = Instant::now();
+    let response = next.run(request).await;
+    if let Some(route) = route {
+        METRICS.record_request(&route, response.status().as_u16(), started.elapsed());
+    }
+    response
+}
+
+pub async fn metrics(State(state): State<Arc<AppState>>) -> Response {
+    (
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8"),
+        )],
+        METRICS.render(&state),
+    )
+        .into_response()
+}
@@ -0,0 +1,123 @@
+use reqwest::Url;
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::net::lookup_host;
+
+/// Addresses of `url`'s host, refusing any that aren't on the public internet unless
+/// `allow_local`, so clients can't have the server send requests into its own network.
+pub async fn resolve_public(url: &Url, allow_local: bool) -> Result<Vec<SocketAddr>, String> {
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| format!("no port for {}", url))?;
+    let addrs: Vec<SocketAddr> = match url.domain() {
+        Some(domain) => lookup_host((domain, port))
+            .await
+            .map_err(|err| format!("could not resolve {}: {}", domain, err))?
+            .collect(),
+        // IP hosts, with IPv6 in brackets
+        None => {
+            let ip: IpAddr = url
+                .host_str()
+                .map(|host| host.trim_start_matches('[').trim_end_matches(']'))
+                .and_then(|host| host.parse().ok())
+                .ok_or_else(|| format!("no host in {}", url))?;
+            vec![SocketAddr::new(ip, port)]
+        }
+    };
+    if addrs.is_empty() {
+        return Err(format!("no addresses for {}", url));
+    }
+    if !allow_local {
+        if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+            return Err(format!("{} is not a public address", addr.ip()));
+        }
+    }
+    Ok(addrs)
+}
+
+/// A client for requests to `url` connecting only to the addresses checked here, so the host
+/// can't resolve to somewhere else in between. Redirects aren't followed, as they could lead
+/// anywhere.
+pub async fn client_for(
+    url: &Url,
+    timeout: Duration,
+    allow_local: bool,
+) -> Result<reqwest::Client, String> {
+    let addrs = resolve_public(url, allow_local).await?;
+    let mut builder = reqwest::Client::builder()
+        .timeout(timeout)
+        .redirect(reqwest::redirect::Policy::none());
+    if let Some(domain) = url.domain() {
+        builder = builder.resolve_to_addrs(domain, &addrs);
+    }
+    builder.build().map_err(|err| err.to_string())
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // unique local fc00::/7
+                    || first & 0xfe00 == 0xfc00
+                    // link-local fe80::/10
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [first, second, ..] = ip.octets();
+    !(ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // "this network" 0.0.0.0/8
+        || first == 0
+        // carrier-grade NAT 100.64.0.0/10
+        || (first == 100 && second & 0xc0 == 64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn refuses_local_addresses() {
+        for url in [
+            "http://127.0.0.1:8080/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://10.1.2.3/",
+            "http://192.168.0.1/",
+            "http://0.0.0.0/",
+            "http://[::1]/",
+            "http://[fd00::1]/",
+            "http://[fe80::1]/",
+            "http://[::ffff:127.0.0.1]/",
+            "http://localhost/",
+        ] {
+            let url = Url::parse(url).unwrap();
+            assert!(resolve_public(&url, false).await.is_err(), "{}", url);
+            assert!(resolve_public(&url, true).await.is_ok(), "{}", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn accepts_public_addresses() {
+        for url in ["https://1.1.1.1/hook", "http://[2606:4700::1111]/"] {
+            let url = Url::parse(url).unwrap();
+            assert!(resolve_public(&url, false).await.is_ok(), "{}", url);
+        }
+    }
+}
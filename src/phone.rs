@@ -1,33 +1,41 @@
 use chrono::{DateTime, Utc};
 use chrono_tz::Tz;
 
-use crate::silam::{Pollen, PollenIndex};
+use crate::{
+    daily::DaySummary,
+    error::AppError,
+    silam::{Pollen, PollenIndex},
+};
 
 fn get_spoken_time(time: &DateTime<Utc>, tz: &Tz) -> String {
     time.with_timezone(tz).format("%-I%P").to_string()
 }
 
-fn is_pollen_very_low(pollen: &Pollen) -> bool {
-    pollen.pollen_index == PollenIndex::VeryLow || pollen.pollen_index == PollenIndex::Unknown
+fn is_pollen_very_low(index: PollenIndex) -> bool {
+    index == PollenIndex::VeryLow || index == PollenIndex::Unknown
 }
 
-pub fn get_phone_text(pollen_three_day: &Vec<Pollen>, now_index: usize, tz: Tz) -> String {
-    let pollen_now = pollen_three_day.get(now_index).unwrap();
-    let chunked_pollen = pollen_three_day.chunks(24).collect::<Vec<&[Pollen]>>();
+fn get_tomorrow_text(tomorrow: &DaySummary, tz: &Tz) -> String {
+    if is_pollen_very_low(tomorrow.max_index) {
+        format!("Tomorrow pollen will be very low all day.")
+    } else {
+        format!(
+            "Tomorrow's high will be {} {} at {}.",
+            tomorrow.max_index.to_spoken(),
+            tomorrow.peak_source.to_spoken(),
+            get_spoken_time(&tomorrow.peak_time, tz)
+        )
+    }
+}
 
-    let [pollen_today, pollen_tomorrow, pollen_day_after] = <[&Pollen; 3]>::try_from(
-        chunked_pollen
-            .iter()
-            .map(|chunk| {
-                chunk
-                    .iter()
-                    .max_by(|a, b| a.pollen_index.cmp(&b.pollen_index))
-                    .unwrap()
-            })
-            .collect::<Vec<&Pollen>>(),
-    )
-    .ok()
-    .unwrap();
+pub fn get_phone_text(
+    pollen_now: &Pollen,
+    days: &[DaySummary],
+    tz: Tz,
+) -> Result<String, AppError> {
+    let [today, tomorrow, day_after] = days else {
+        return Err(AppError::DataUnavailable);
+    };
 
     let before_text = "Hello.";
     let now_text = format!(
@@ -35,49 +43,39 @@ pub fn get_phone_text(pollen_three_day: &Vec<Pollen>, now_index: usize, tz: Tz)
         pollen_now.pollen_index.to_spoken(),
         pollen_now.pollen_index_source.to_spoken()
     );
-    let today_text = if pollen_today.time == pollen_now.time
-        || pollen_today.pollen_index == pollen_now.pollen_index
-    {
-        format!("It is currently at today's high.")
-    } else if is_pollen_very_low(pollen_today) {
-        format!("Pollen will be very low all day.")
-    } else if pollen_today.time < pollen_now.time {
-        format!(
-            "Today's high was {} {} at {}.",
-            pollen_today.pollen_index.to_spoken(),
-            pollen_today.pollen_index_source.to_spoken(),
-            get_spoken_time(&pollen_today.time, &tz)
-        )
-    } else {
-        format!(
-            "Today's high will be {} {} at {}.",
-            pollen_today.pollen_index.to_spoken(),
-            pollen_today.pollen_index_source.to_spoken(),
-            get_spoken_time(&pollen_today.time, &tz)
-        )
-    };
-    let tomorrow_text = if is_pollen_very_low(pollen_tomorrow) {
-        format!("Tomorrow pollen will be very low all day.")
-    } else {
-        format!(
-            "Tomorrow's high will be {} {} at {}.",
-            pollen_tomorrow.pollen_index.to_spoken(),
-            pollen_tomorrow.pollen_index_source.to_spoken(),
-            get_spoken_time(&pollen_tomorrow.time, &tz)
-        )
-    };
-    let day_after_text = if is_pollen_very_low(pollen_day_after) {
+    let today_text =
+        if today.peak_time == pollen_now.time || today.max_index == pollen_now.pollen_index {
+            format!("It is currently at today's high.")
+        } else if is_pollen_very_low(today.max_index) {
+            format!("Pollen will be very low all day.")
+        } else if today.peak_time < pollen_now.time {
+            format!(
+                "Today's high was {} {} at {}.",
+                today.max_index.to_spoken(),
+                today.peak_source.to_spoken(),
+                get_spoken_time(&today.peak_time, &tz)
+            )
+        } else {
+            format!(
+                "Today's high will be {} {} at {}.",
+                today.max_index.to_spoken(),
+                today.peak_source.to_spoken(),
+                get_spoken_time(&today.peak_time, &tz)
+            )
+        };
+    let tomorrow_text = get_tomorrow_text(tomorrow, &tz);
+    let day_after_text = if is_pollen_very_low(day_after.max_index) {
         format!(
             "{}'s pollen will be very low all day.",
-            pollen_day_after.time.format("%A")
+            day_after.date.format("%A")
         )
     } else {
         format!(
             "{}'s high will be {} {} at {}.",
-            pollen_day_after.time.format("%A"),
-            pollen_day_after.pollen_index.to_spoken(),
-            pollen_day_after.pollen_index_source.to_spoken(),
-            get_spoken_time(&pollen_day_after.time, &tz)
+            day_after.date.format("%A"),
+            day_after.max_index.to_spoken(),
+            day_after.peak_source.to_spoken(),
+            get_spoken_time(&day_after.peak_time, &tz)
         )
     };
     let after_text = "Thank you for calling the EMF pollen hotline. Data provided by Finnish Meteorological Institute and European Aeroallergen Network. Goodbye.";
@@ -85,5 +83,28 @@ pub fn get_phone_text(pollen_three_day: &Vec<Pollen>, now_index: usize, tz: Tz)
         "{} {} {} {} {} {}",
         before_text, now_text, today_text, tomorrow_text, day_after_text, after_text
     );
-    text
+    Ok(text)
+}
+
+/// Today's and tomorrow's highs, worded to read the same whatever time it is read at.
+pub fn get_outlook_text(days: &[DaySummary], tz: Tz) -> Result<String, AppError> {
+    let [today, tomorrow, ..] = days else {
+        return Err(AppError::DataUnavailable);
+    };
+
+    let today_text = if is_pollen_very_low(today.max_index) {
+        "Today pollen will be very low all day.".to_string()
+    } else {
+        format!(
+            "Today's high is {} {} at {}.",
+            today.max_index.to_spoken(),
+            today.peak_source.to_spoken(),
+            get_spoken_time(&today.peak_time, &tz)
+        )
+    };
+    Ok(format!(
+        "{} {}",
+        today_text,
+        get_tomorrow_text(tomorrow, &tz)
+    ))
 }
@@ -0,0 +1,477 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Days, Duration, NaiveDate, Utc};
+use reqwest::{header, StatusCode};
+use ring::{
+    aead, agreement, hkdf,
+    rand::{SecureRandom, SystemRandom},
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{fs, path::Path, sync::Arc, time::Duration as StdDuration};
+
+use crate::{
+    calendar::capitalise,
+    daily::summarise_days,
+    error::AppError,
+    html::forecast_query,
+    outbound,
+    secret::random_hex,
+    silam::{Interpolation, PollenIndex},
+    source::write_atomic,
+    store::JsonStore,
+    window::ForecastWindow,
+    AppState,
+};
+
+const DELIVERY_TIMEOUT: StdDuration = StdDuration::from_secs(10);
+/// How long push services should keep trying to reach an offline browser.
+const TTL_SECONDS: u32 = 24 * 60 * 60;
+/// Record size for the single record payloads are encrypted into.
+const RECORD_SIZE: u32 = 4096;
+const VAPID_KEY_FILE: &str = "vapid.pk8";
+/// Subscriptions kept in all.
+const MAX_SUBSCRIPTIONS: usize = 50_000;
+/// Locations one browser can be subscribed to.
+const MAX_SUBSCRIPTIONS_PER_ENDPOINT: usize = 20;
+
+/// A browser's push subscription for the high pollen forecast at a location.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PushSubscription {
+    pub id: String,
+    pub endpoint: String,
+    /// The browser's P-256 public key, base64url.
+    pub p256dh: String,
+    /// The browser's authentication secret, base64url.
+    pub auth: String,
+    pub lon: f32,
+    pub lat: f32,
+    pub interpolation: Interpolation,
+    pub threshold: PollenIndex,
+    pub created: DateTime<Utc>,
+    /// Local date last notified about, so each day is only pushed once.
+    notified_for: Option<NaiveDate>,
+}
+
+/// A checked request to subscribe a browser.
+pub struct NewPushSubscription {
+    pub endpoint: String,
+    pub p256dh: String,
+    pub auth: String,
+    pub lon: f32,
+    pub lat: f32,
+    pub interpolation: Interpolation,
+    pub threshold: PollenIndex,
+}
+
+/// Push subscriptions, kept in `push.json` in the data directory if one is configured, and the
+/// VAPID key that identifies this server to push services.
+pub struct PushStore {
+    subscriptions: JsonStore<PushSubscription>,
+    vapid_key: EcdsaKeyPair,
+    /// Contact for push services, a `mailto:` or `https:` URL.
+    subject: String,
+    /// Whether endpoints may be on loopback and private addresses, for testing against a local
+    /// push service.
+    allow_local_targets: bool,
+    random: SystemRandom,
+}
+
+impl PushStore {
+    /// `private_key` is a base64url PKCS#8 P-256 key. Without one, a key is generated and kept
+    /// in the data directory, as browsers have to subscribe again whenever it changes.
+    pub fn open(
+        data_dir: Option<&Path>,
+        private_key: Option<&str>,
+        subject: String,
+        allow_local_targets: bool,
+    ) -> Result<PushStore, Box<dyn std::error::Error>> {
+        let random = SystemRandom::new();
+        let key_path = data_dir.map(|dir| dir.join(VAPID_KEY_FILE));
+        let pkcs8 = match (private_key, &key_path) {
+            (Some(key), _) => URL_SAFE_NO_PAD.decode(key.trim_end_matches('='))?,
+            (None, Some(path)) if path.exists() => fs::read(path)?,
+            (None, _) => {
+                let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &random)
+                    .map_err(|_| "failed to generate VAPID key")?;
+                if let Some(path) = &key_path {
+                    fs::create_dir_all(path.parent().unwrap_or(Path::new(".")))?;
+                    write_atomic(path, pkcs8.as_ref())?;
+                }
+                pkcs8.as_ref().to_vec()
+            }
+        };
+        let vapid_key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &random)
+            .map_err(|err| format!("invalid VAPID key: {}", err))?;
+
+        Ok(PushStore {
+            subscriptions: JsonStore::open(data_dir, "push.json")?,
+            vapid_key,
+            subject,
+            allow_local_targets,
+            random,
+        })
+    }
+
+    /// Application server key browsers subscribe with, base64url.
+    pub fn public_key(&self) -> String {
+        URL_SAFE_NO_PAD.encode(self.vapid_key.public_key().as_ref())
+    }
+
+    /// Adds the subscription, or returns the existing one for the same browser and location.
+    pub async fn subscribe(&self, new: NewPushSubscription) -> Result<PushSubscription, AppError> {
+        decode_key(&new.p256dh, 65, "p256dh")?;
+        decode_key(&new.auth, 16, "auth")?;
+        let endpoint = reqwest::Url::parse(&new.endpoint)
+            .map_err(|err| AppError::InvalidBody(format!("endpoint is not valid: {}", err)))?;
+        outbound::resolve_public(&endpoint, self.allow_local_targets)
+            .await
+            .map_err(|err| AppError::InvalidBody(format!("endpoint can't be used: {}", err)))?;
+
+        self.subscriptions.update(|subscriptions| {
+            if let Some(existing) = subscriptions.iter_mut().find(|subscription| {
+                subscription.endpoint == new.endpoint
+                    && subscription.lon == new.lon
+                    && subscription.lat == new.lat
+                    && subscription.interpolation == new.interpolation
+            }) {
+                // browsers may rotate their keys for the same endpoint
+                existing.p256dh = new.p256dh;
+                existing.auth = new.auth;
+                existing.threshold = new.threshold;
+                return Ok(existing.clone());
+            }
+            let for_endpoint = subscriptions
+                .iter()
+                .filter(|subscription| subscription.endpoint == new.endpoint)
+                .count();
+            if subscriptions.len() >= MAX_SUBSCRIPTIONS
+                || for_endpoint >= MAX_SUBSCRIPTIONS_PER_ENDPOINT
+            {
+                return Err(AppError::TooManySubscriptions);
+            }
+
+            let subscription = PushSubscription {
+                id: random_hex(16)?,
+                endpoint: new.endpoint,
+                p256dh: new.p256dh,
+                auth: new.auth,
+                lon: new.lon,
+                lat: new.lat,
+                interpolation: new.interpolation,
+                threshold: new.threshold,
+                created: Utc::now(),
+                notified_for: None,
+            };
+            subscriptions.push(subscription.clone());
+            Ok(subscription)
+        })
+    }
+
+    /// Removes the browser's subscription for the location, or all of its subscriptions if no
+    /// location is given. Only the browser knows its endpoint, so it serves as the credential.
+    pub fn unsubscribe(
+        &self,
+        endpoint: &str,
+        location: Option<(f32, f32, Interpolation)>,
+    ) -> Result<(), AppError> {
+        self.subscriptions.update(|subscriptions| {
+            let before = subscriptions.len();
+            subscriptions.retain(|subscription| {
+                subscription.endpoint != endpoint
+                    || location.is_some_and(|(lon, lat, interpolation)| {
+                        (
+                            subscription.lon,
+                            subscription.lat,
+                            subscription.interpolation,
+                        ) != (lon, lat, interpolation)
+                    })
+            });
+            if subscriptions.len() == before {
+                return Err(AppError::SubscriptionNotFound);
+            }
+            Ok(())
+        })
+    }
+
+    fn remove(&self, id: &str) -> Result<(), AppError> {
+        self.subscriptions.update(|subscriptions| {
+            subscriptions.retain(|subscription| subscription.id != id);
+            Ok(())
+        })
+    }
+
+    fn mark_notified(&self, id: &str, date: NaiveDate) -> Result<(), AppError> {
+        self.subscriptions.update(|subscriptions| {
+            if let Some(subscription) = subscriptions
+                .iter_mut()
+                .find(|subscription| subscription.id == id)
+            {
+                subscription.notified_for = Some(date);
+            }
+            Ok(())
+        })
+    }
+
+    async fn send(
+        &self,
+        subscription: &PushSubscription,
+        payload: &[u8],
+    ) -> Result<StatusCode, AppError> {
+        let body = self.encrypt(subscription, payload)?;
+        let endpoint = reqwest::Url::parse(&subscription.endpoint)
+            .map_err(|err| AppError::Internal(format!("invalid push endpoint: {}", err)))?;
+        // checked again as the host may resolve somewhere else since it subscribed
+        let client = outbound::client_for(&endpoint, DELIVERY_TIMEOUT, self.allow_local_targets)
+            .await
+            .map_err(|err| AppError::Internal(format!("push endpoint refused: {}", err)))?;
+        let response = client
+            .post(endpoint)
+            .header(
+                header::AUTHORIZATION,
+                self.vapid_authorization(subscription)?,
+            )
+            .header(header::CONTENT_ENCODING, "aes128gcm")
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .header("TTL", TTL_SECONDS)
+            .body(body)
+            .send()
+            .await
+            .map_err(|err| AppError::Internal(format!("push delivery failed: {}", err)))?;
+        Ok(response.status())
+    }
+
+    /// Signed JWT telling the push service who is sending, as VAPID (RFC 8292) requires.
+    fn vapid_authorization(&self, subscription: &PushSubscription) -> Result<String, AppError> {
+        let audience = reqwest::Url::parse(&subscription.endpoint)
+            .map_err(|err| AppError::Internal(format!("invalid push endpoint: {}", err)))?
+            .origin()
+            .ascii_serialization();
+        let header = URL_SAFE_NO_PAD.encode(r#"{"typ":"JWT","alg":"ES256"}"#);
+        let claims = URL_SAFE_NO_PAD.encode(
+            json!({
+                "aud": audience,
+                "exp": (Utc::now() + Duration::hours(12)).timestamp(),
+                "sub": self.subject,
+            })
+            .to_string(),
+        );
+        let message = format!("{}.{}", header, claims);
+        let signature = self
+            .vapid_key
+            .sign(&self.random, message.as_bytes())
+            .map_err(|_| AppError::Internal("failed to sign VAPID token".to_string()))?;
+        Ok(format!(
+            "vapid t={}.{}, k={}",
+            message,
+            URL_SAFE_NO_PAD.encode(signature.as_ref()),
+            self.public_key()
+        ))
+    }
+
+    /// Encrypts the payload for the browser as one aes128gcm record (RFC 8291).
+    fn encrypt(
+        &self,
+        subscription: &PushSubscription,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, AppError> {
+        let crypto_error = |_| AppError::Internal("failed to encrypt push payload".to_string());
+        let ua_public = decode_key(&subscription.p256dh, 65, "p256dh")?;
+        let auth = decode_key(&subscription.auth, 16, "auth")?;
+
+        let as_private =
+            agreement::EphemeralPrivateKey::generate(&agreement::ECDH_P256, &self.random)
+                .map_err(crypto_error)?;
+        let as_public = as_private.compute_public_key().map_err(crypto_error)?;
+        let as_public = as_public.as_ref();
+        let ecdh_secret = agreement::agree_ephemeral(
+            as_private,
+            &agreement::UnparsedPublicKey::new(&agreement::ECDH_P256, &ua_public),
+            |secret| secret.to_vec(),
+        )
+        .map_err(crypto_error)?;
+
+        let key_info = [b"WebPush: info\0".as_slice(), &ua_public, as_public].concat();
+        let ikm = hkdf_sha256(&auth, &ecdh_secret, &key_info, 32)?;
+        let mut salt = [0; 16];
+        self.random.fill(&mut salt).map_err(crypto_error)?;
+        let cek = hkdf_sha256(&salt, &ikm, b"Content-Encoding: aes128gcm\0", 16)?;
+        let nonce = hkdf_sha256(&salt, &ikm, b"Content-Encoding: nonce\0", 12)?;
+
+        let key = aead::LessSafeKey::new(
+            aead::UnboundKey::new(&aead::AES_128_GCM, &cek).map_err(crypto_error)?,
+        );
+        let nonce = aead::Nonce::try_assume_unique_for_key(&nonce).map_err(crypto_error)?;
+        // delimiter marking the last and only record
+        let mut record = [payload, &[2]].concat();
+        key.seal_in_place_append_tag(nonce, aead::Aad::empty(), &mut record)
+            .map_err(crypto_error)?;
+
+        let mut body = Vec::with_capacity(16 + 4 + 1 + as_public.len() + record.len());
+        body.extend_from_slice(&salt);
+        body.extend_from_slice(&RECORD_SIZE.to_be_bytes());
+        body.push(as_public.len() as u8);
+        body.extend_from_slice(as_public);
+        body.extend_from_slice(&record);
+        Ok(body)
+    }
+}
+
+/// After a refresh, pushes to each subscription whose location is forecast to reach its
+/// threshold tomorrow, once per day.
+pub async fn notify_push(state: Arc<AppState>) {
+    let silam = state.silam.load_full();
+    let now = Utc::now();
+
+    for subscription in state.push.subscriptions.list() {
+        let result = async {
+            let tz = state.timezone(subscription.lon, subscription.lat)?;
+            let Some(tomorrow) = now
+                .with_timezone(&tz)
+                .date_naive()
+                .checked_add_days(Days::new(1))
+            else {
+                return Ok(());
+            };
+            if subscription.notified_for >= Some(tomorrow) {
+                return Ok(());
+            }
+            let window =
+                ForecastWindow::whole_days(tomorrow, 1, &tz).ok_or(AppError::DataUnavailable)?;
+            let pollen = silam.get_window(
+                &subscription.lon,
+                &subscription.lat,
+                subscription.interpolation,
+                &window,
+            )?;
+            let Some(day) = summarise_days(&pollen, &tz).into_iter().next() else {
+                return Ok(());
+            };
+            if day.max_index < subscription.threshold {
+                return Ok(());
+            }
+
+            let place = &state.nearest_place(subscription.lon, subscription.lat).name;
+            let payload = json!({
+                "title": "pollen.party",
+                "body": format!(
+                    "{} {} pollen tomorrow in {}",
+                    capitalise(day.max_index.to_spoken()),
+                    day.peak_source.to_spoken(),
+                    place
+                ),
+                "url": format!(
+                    "/?{}",
+                    forecast_query(subscription.lon, subscription.lat, subscription.interpolation)
+                ),
+            });
+            match state
+                .push
+                .send(&subscription, payload.to_string().as_bytes())
+                .await?
+            {
+                status if status.is_success() => {
+                    state.push.mark_notified(&subscription.id, tomorrow)
+                }
+                // the browser unsubscribed or the subscription expired
+                StatusCode::NOT_FOUND | StatusCode::GONE => state.push.remove(&subscription.id),
+                status => Err(AppError::Internal(format!(
+                    "push service responded {}",
+                    status
+                ))),
+            }
+        }
+        .await;
+        if let Err(err) = result {
+            println!(
+                "Failed to push to subscription {}: {}",
+                subscription.id, err
+            );
+        }
+    }
+}
+
+fn decode_key(key: &str, len: usize, name: &str) -> Result<Vec<u8>, AppError> {
+    URL_SAFE_NO_PAD
+        .decode(key.trim_end_matches('='))
+        .ok()
+        .filter(|bytes| bytes.len() == len)
+        .ok_or_else(|| {
+            AppError::InvalidBody(format!("{} must be {} bytes of base64url", name, len))
+        })
+}
+
+/// Output length for `hkdf_sha256`.
+struct Len(usize);
+
+impl hkdf::KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+fn hkdf_sha256(salt: &[u8], ikm: &[u8], info: &[u8], len: usize) -> Result<Vec<u8>, AppError> {
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(ikm);
+    let mut out = vec![0; len];
+    prk.expand(&[info], Len(len))
+        .and_then(|okm| okm.fill(&mut out))
+        .map_err(|_| AppError::Internal("failed to derive push keys".to_string()))?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_subscription(endpoint: &str, lon: f32) -> NewPushSubscription {
+        NewPushSubscription {
+            endpoint: endpoint.to_string(),
+            p256dh: URL_SAFE_NO_PAD.encode([4; 65]),
+            auth: URL_SAFE_NO_PAD.encode([1; 16]),
+            lon,
+            lat: 60.17,
+            interpolation: Interpolation::Nearest,
+            threshold: PollenIndex::High,
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_local_endpoints_unless_allowed() {
+        let endpoint = "https://127.0.0.1/push/abc";
+        let store =
+            PushStore::open(None, None, "mailto:test@example.com".to_string(), false).unwrap();
+        assert!(matches!(
+            store.subscribe(new_subscription(endpoint, 24.94)).await,
+            Err(AppError::InvalidBody(_))
+        ));
+
+        let store =
+            PushStore::open(None, None, "mailto:test@example.com".to_string(), true).unwrap();
+        store
+            .subscribe(new_subscription(endpoint, 24.94))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn caps_locations_per_endpoint() {
+        let endpoint = "https://127.0.0.1/push/abc";
+        let store =
+            PushStore::open(None, None, "mailto:test@example.com".to_string(), true).unwrap();
+        for lon in 0..MAX_SUBSCRIPTIONS_PER_ENDPOINT {
+            store
+                .subscribe(new_subscription(endpoint, lon as f32))
+                .await
+                .unwrap();
+        }
+        // updating an existing one is still fine
+        store
+            .subscribe(new_subscription(endpoint, 0.0))
+            .await
+            .unwrap();
+        assert!(matches!(
+            store.subscribe(new_subscription(endpoint, 99.0)).await,
+            Err(AppError::TooManySubscriptions)
+        ));
+    }
+}
@@ -0,0 +1,100 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    cmp::min,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::time;
+
+use crate::{
+    metrics::METRICS, push::notify_push, source::SilamVersion, webhooks::notify_webhooks, AppState,
+};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const INITIAL_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// Outcome of the most recent attempts to refresh SILAM data.
+#[derive(Serialize, Clone, Default)]
+pub struct RefreshStatus {
+    pub last_attempt: Option<DateTime<Utc>>,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub consecutive_failures: u32,
+}
+
+/// Polls the source for newer data and replaces the SILAM data when it has some, retrying
+/// failures with exponential backoff while the previous data keeps being served. THREDDS is
+/// only polled once a new model run is expected, local files are checked every time.
+pub async fn silam_refetch_if_stale(state: Arc<AppState>) {
+    let mut interval = time::interval(CHECK_INTERVAL);
+    let mut last_poll: Option<Instant> = None;
+    let remote = state.silam_source.is_remote();
+    loop {
+        interval.tick().await;
+        let current = {
+            let silam = state.silam.load();
+            if remote && !silam.is_stale() {
+                continue;
+            }
+            SilamVersion::from(&**silam)
+        };
+        if remote && last_poll.is_some_and(|last_poll| last_poll.elapsed() < POLL_INTERVAL) {
+            continue;
+        }
+        last_poll = Some(Instant::now());
+
+        let attempt_time = Utc::now();
+        match state.silam_source.has_newer(&current).await {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(err) => {
+                println!("Failed to check for new SILAM data: {}", err);
+                record_failure(&state, attempt_time, err.to_string());
+                continue;
+            }
+        }
+
+        let mut backoff = INITIAL_BACKOFF;
+        while !refresh(&state).await {
+            println!("Retrying SILAM refresh in {}s", backoff.as_secs());
+            time::sleep(backoff).await;
+            backoff = min(backoff * 2, MAX_BACKOFF);
+        }
+    }
+}
+
+async fn refresh(state: &Arc<AppState>) -> bool {
+    let attempt_time = Utc::now();
+    match state.silam_source.load().await {
+        Ok(silam) => {
+            // readers holding the previous snapshot keep it until their request finishes
+            state.silam.store(Arc::new(silam));
+            state.silam_installed.send_replace(());
+            let mut status = state.refresh_status.write().unwrap();
+            status.last_attempt = Some(attempt_time);
+            status.last_success = Some(attempt_time);
+            status.last_error = None;
+            status.consecutive_failures = 0;
+            METRICS.record_refresh(true);
+            tokio::spawn(notify_webhooks(Arc::clone(state)));
+            tokio::spawn(notify_push(Arc::clone(state)));
+            true
+        }
+        Err(err) => {
+            println!("Failed to refresh SILAM data: {}", err);
+            record_failure(state, attempt_time, err.to_string());
+            false
+        }
+    }
+}
+
+fn record_failure(state: &AppState, attempt_time: DateTime<Utc>, error: String) {
+    METRICS.record_refresh(false);
+    let mut status = state.refresh_status.write().unwrap();
+    status.last_attempt = Some(attempt_time);
+    status.last_error = Some(error);
+    status.consecutive_failures += 1;
+}
@@ -0,0 +1,54 @@
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+
+use crate::error::AppError;
+
+/// `len` random bytes as hex, for ids and secrets.
+pub fn random_hex(len: usize) -> Result<String, AppError> {
+    let mut bytes = vec![0; len];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| AppError::Internal("no randomness available".to_string()))?;
+    Ok(hex(&bytes))
+}
+
+/// Hex HMAC-SHA256 of `message`.
+pub fn sign(key: &[u8], message: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    hex(hmac::sign(&key, message).as_ref())
+}
+
+/// Whether `signature` is the hex HMAC-SHA256 of `message`, compared in constant time.
+pub fn verify(key: &[u8], message: &[u8], signature: &str) -> bool {
+    let Some(signature) = unhex(signature) else {
+        return false;
+    };
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    hmac::verify(&key, message, &signature).is_ok()
+}
+
+/// Whether `given` is `expected`, compared as HMACs so the time taken reveals nothing about
+/// `expected`.
+pub fn matches(expected: &str, given: &str) -> bool {
+    verify(
+        expected.as_bytes(),
+        b"token",
+        &sign(given.as_bytes(), b"token"),
+    )
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
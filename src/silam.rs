@@ -1,10 +1,18 @@
-use chrono::{DateTime, Duration, NaiveTime, SecondsFormat, Utc};
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, NaiveTime, SecondsFormat, Utc};
 use ndarray::{s, Array3, Ix3};
+use netcdf::AttributeValue;
 use proj4rs::Proj;
-use serde::Serialize;
-use std::{cmp::max, fmt::Display};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::{max, min},
+    fmt::Display,
+    fs,
+    path::Path,
+};
 
-#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+use crate::{error::AppError, window::ForecastWindow};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum PollenIndex {
     Unknown,
     VeryLow,
@@ -15,6 +23,16 @@ pub enum PollenIndex {
 }
 
 impl PollenIndex {
+    /// Every level, lowest first.
+    pub const LEVELS: [PollenIndex; 6] = [
+        PollenIndex::Unknown,
+        PollenIndex::VeryLow,
+        PollenIndex::Low,
+        PollenIndex::Moderate,
+        PollenIndex::High,
+        PollenIndex::VeryHigh,
+    ];
+
     pub fn from_raw(raw: &f32) -> PollenIndex {
         match *raw as i32 {
             1 => PollenIndex::VeryLow,
@@ -26,6 +44,19 @@ impl PollenIndex {
         }
     }
 
+    /// Level from its spoken name or its number, for levels that can be forecast.
+    pub fn from_name(name: &str) -> Option<PollenIndex> {
+        PollenIndex::LEVELS
+            .into_iter()
+            .filter(|level| *level != PollenIndex::Unknown)
+            .find(|level| {
+                level
+                    .to_spoken()
+                    .eq_ignore_ascii_case(&name.replace(['_', '-'], " "))
+                    || level.to_string() == name
+            })
+    }
+
     pub fn to_spoken(&self) -> &str {
         match self {
             PollenIndex::Unknown => "unknown",
@@ -51,7 +82,7 @@ impl Display for PollenIndex {
     }
 }
 
-#[derive(Debug, Serialize, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub enum PollenType {
     Unknown = -1,
     Alder = 1,
@@ -87,6 +118,35 @@ impl PollenType {
             PollenType::Ragweed => "ragweed",
         }
     }
+
+    /// Species for which SILAM provides a concentration field, in the order they are stored.
+    pub const SPECIES: [PollenType; 6] = [
+        PollenType::Alder,
+        PollenType::Birch,
+        PollenType::Grass,
+        PollenType::Olive,
+        PollenType::Mugwort,
+        PollenType::Ragweed,
+    ];
+
+    pub fn from_name(name: &str) -> Option<PollenType> {
+        PollenType::SPECIES
+            .into_iter()
+            .find(|species| species.to_spoken().eq_ignore_ascii_case(name))
+    }
+
+    /// Name of the SILAM variable holding the surface concentration of this species.
+    fn silam_variable(&self) -> Option<&'static str> {
+        match self {
+            PollenType::Unknown => None,
+            PollenType::Alder => Some("cnc_POLLEN_ALDER_m22"),
+            PollenType::Birch => Some("cnc_POLLEN_BIRCH_m22"),
+            PollenType::Grass => Some("cnc_POLLEN_GRASS_m32"),
+            PollenType::Olive => Some("cnc_POLLEN_OLIVE_m28"),
+            PollenType::Mugwort => Some("cnc_POLLEN_MUGW_m18"),
+            PollenType::Ragweed => Some("cnc_POLLEN_RAGW_m18"),
+        }
+    }
 }
 
 impl Display for PollenType {
@@ -103,24 +163,127 @@ impl Display for PollenType {
     }
 }
 
+/// Surface concentrations in grains/m³ for each species.
+#[derive(Debug, Serialize, Clone, Copy, Default)]
+pub struct Concentrations {
+    pub alder: f32,
+    pub birch: f32,
+    pub grass: f32,
+    pub olive: f32,
+    pub mugwort: f32,
+    pub ragweed: f32,
+}
+
+impl Concentrations {
+    pub fn get(&self, species: PollenType) -> f32 {
+        match species {
+            PollenType::Unknown => 0.0,
+            PollenType::Alder => self.alder,
+            PollenType::Birch => self.birch,
+            PollenType::Grass => self.grass,
+            PollenType::Olive => self.olive,
+            PollenType::Mugwort => self.mugwort,
+            PollenType::Ragweed => self.ragweed,
+        }
+    }
+
+    fn combine(&mut self, other: &Concentrations, f: impl Fn(f32, f32) -> f32) {
+        for species in PollenType::SPECIES {
+            self.set(species, f(self.get(species), other.get(species)));
+        }
+    }
+
+    fn set(&mut self, species: PollenType, value: f32) {
+        match species {
+            PollenType::Unknown => {}
+            PollenType::Alder => self.alder = value,
+            PollenType::Birch => self.birch = value,
+            PollenType::Grass => self.grass = value,
+            PollenType::Olive => self.olive = value,
+            PollenType::Mugwort => self.mugwort = value,
+            PollenType::Ragweed => self.ragweed = value,
+        }
+    }
+}
+
+/// How values are derived from the grid cells surrounding a requested point.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Interpolation {
+    /// Value of the single closest cell.
+    #[default]
+    Nearest,
+    /// Weighted by distance over the four surrounding cells.
+    Bilinear,
+    /// Highest value over the closest cell and its eight neighbours.
+    Max,
+}
+
 #[derive(Serialize, Clone, Copy)]
 pub struct Pollen {
     pub time: DateTime<Utc>,
     pub pollen_index: PollenIndex,
     pub pollen_index_source: PollenType,
+    pub concentrations: Concentrations,
+}
+
+impl Pollen {
+    /// No value, for a point with no cells the model covers.
+    fn unknown(time: DateTime<Utc>) -> Pollen {
+        Pollen {
+            time,
+            pollen_index: PollenIndex::Unknown,
+            pollen_index_source: PollenType::Unknown,
+            concentrations: Concentrations::default(),
+        }
+    }
 }
 
+const THREDDS_URL: &str = "https://thredds.silam.fmi.fi/thredds";
+
+/// SILAM Europe pollen is run once a day.
+const RUN_INTERVAL: Duration = Duration::hours(24);
+
+/// Roughly how long after its nominal time a run is published on THREDDS.
+const PUBLISH_DELAY: Duration = Duration::hours(2);
+
 pub struct Silam {
     pub fetch_time: DateTime<Utc>,
     pub start_time: DateTime<Utc>,
+    pub run_time: DateTime<Utc>,
     poli: Array3<f32>,
     polisrc: Array3<f32>,
+    cnc: Vec<Array3<f32>>, // one per PollenType::SPECIES
     rlats: Vec<f32>,
     rlons: Vec<f32>,
 }
 
 impl Silam {
-    pub async fn fetch(silam_email: &Option<String>) -> Result<Silam, Box<dyn std::error::Error>> {
+    /// Looks up the most recent model run in the THREDDS catalog, which is far cheaper than
+    /// downloading the data to find out.
+    pub async fn fetch_latest_run_time() -> Result<DateTime<Utc>, Box<dyn std::error::Error>> {
+        let catalog_url = format!(
+            "{}/catalog/silam_europe_pollen_v6_0/runs/catalog.xml",
+            THREDDS_URL
+        );
+        let catalog = reqwest::get(catalog_url)
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        parse_run_times(&catalog)
+            .into_iter()
+            .max()
+            .ok_or_else(|| "no runs listed in THREDDS catalog".into())
+    }
+
+    /// Downloads from THREDDS, returning the raw NetCDF bytes alongside the parsed data.
+    /// `polled_run_time` is the latest run in the catalog when it was checked, used if the
+    /// download doesn't say which run it is from.
+    pub async fn fetch(
+        silam_email: &Option<String>,
+        polled_run_time: Option<DateTime<Utc>>,
+    ) -> Result<(Silam, Vec<u8>), Box<dyn std::error::Error>> {
         let start_time = Utc::now()
             .with_time(NaiveTime::from_hms_opt(0, 0, 0).unwrap())
             .unwrap()
@@ -130,85 +293,431 @@ impl Silam {
             Some(email) => format!("&email={}", email),
             None => String::new(),
         };
+        let cnc_vars_param: String = PollenType::SPECIES
+            .iter()
+            .filter_map(|species| species.silam_variable())
+            .map(|var| format!("&var={}", var))
+            .collect();
         let silam_url = format!(
-            "https://thredds.silam.fmi.fi/thredds/ncss/grid/silam_europe_pollen_v6_0/silam_europe_pollen_v6_0_best.ncd?var=POLI&var=POLISRC&north=75.950&west=-47.600&east=78.059&south=19.003&horizStride=1&accept=netcdf4ext&addLatLon=true&time_start={}&time_end={}{}",
+            "{}/ncss/grid/silam_europe_pollen_v6_0/silam_europe_pollen_v6_0_best.ncd?var=POLI&var=POLISRC{}&north=75.950&west=-47.600&east=78.059&south=19.003&horizStride=1&accept=netcdf4ext&addLatLon=true&time_start={}&time_end={}{}",
+            THREDDS_URL,
+            cnc_vars_param,
             start_time.to_rfc3339_opts(SecondsFormat::Secs, true),
             end_time.to_rfc3339_opts(SecondsFormat::Secs, true),
             silam_email_param,
         );
         println!("Fetching new data from SILAM: {}", silam_url);
-        let body = reqwest::get(silam_url).await?.bytes().await?;
+        let body: Vec<u8> = reqwest::get(silam_url).await?.bytes().await?.into();
+        let fetch_time = Utc::now();
         let file = netcdf::open_mem(None, &body)?;
+        // preferably from the data itself, as a run published since the catalog was polled
+        // would already be in the download, otherwise as `open` does
+        let run_time = read_run_time(&file)
+            .or(polled_run_time)
+            .unwrap_or(fetch_time);
+        let silam = Silam::from_netcdf(&file, fetch_time, read_start_time(&file)?, run_time)?;
+
+        Ok((silam, body))
+    }
+
+    /// Loads a NetCDF file with the same variables as the THREDDS subset requested by `fetch`.
+    pub fn open(path: &Path) -> Result<Silam, Box<dyn std::error::Error>> {
+        println!("Loading SILAM data from file: {}", path.display());
+        let fetch_time: DateTime<Utc> = fs::metadata(path)?.modified()?.into();
+        let file = netcdf::open(path)?;
+        let start_time = read_start_time(&file)?;
+        // files straight from THREDDS carry their run time, otherwise assume a fresh run
+        let run_time = read_run_time(&file).unwrap_or(fetch_time);
 
+        Silam::from_netcdf(&file, fetch_time, start_time, run_time)
+    }
+
+    fn from_netcdf(
+        file: &netcdf::File,
+        fetch_time: DateTime<Utc>,
+        start_time: DateTime<Utc>,
+        run_time: DateTime<Utc>,
+    ) -> Result<Silam, Box<dyn std::error::Error>> {
         let rlons: Vec<f32> = file
             .variable("rlon")
-            .expect("rlon variable missing")
-            .get_values(..)
-            .expect("rlon could not be parsed");
+            .ok_or("rlon variable missing")?
+            .get_values(..)?;
         let rlats: Vec<f32> = file
             .variable("rlat")
-            .expect("rlat variable missing")
-            .get_values(..)
-            .expect("rlat could not be parsed");
-        let poli: Array3<f32> = file
-            .variable("POLI")
-            .expect("POLI variable missing")
-            .get::<f32, _>(..)?
-            .into_dimensionality::<Ix3>()
-            .expect("POLI could not be parsed as Array3");
-        let polisrc: Array3<f32> = file
-            .variable("POLISRC")
-            .expect("POLISRC variable missing")
-            .get::<f32, _>(..)?
-            .into_dimensionality::<Ix3>()
-            .expect("POLISRC could not be parsed as Array3");
+            .ok_or("rlat variable missing")?
+            .get_values(..)?;
+        let poli = read_array3(file, "POLI")?;
+        let polisrc = read_array3(file, "POLISRC")?;
+        let cnc: Vec<Array3<f32>> = PollenType::SPECIES
+            .iter()
+            .filter_map(|species| species.silam_variable())
+            .map(|var| read_array3(file, var))
+            .collect::<Result<_, _>>()?;
 
         Ok(Silam {
-            fetch_time: Utc::now(),
+            fetch_time,
             start_time,
+            run_time,
             poli,
             polisrc,
+            cnc,
             rlats,
             rlons,
         })
     }
 
-    fn stale_at(&self) -> DateTime<Utc> {
-        self.fetch_time + Duration::hours(12)
+    /// When the run after this one should be available to download.
+    pub fn next_run_expected_at(&self) -> DateTime<Utc> {
+        self.run_time + RUN_INTERVAL + PUBLISH_DELAY
+    }
+
+    pub fn grid_cells(&self) -> usize {
+        self.rlats.len() * self.rlons.len()
+    }
+
+    /// End of the last hourly step held.
+    pub fn end_time(&self) -> DateTime<Utc> {
+        self.start_time + Duration::hours(self.poli.shape()[0] as i64)
     }
 
     pub fn time_until_stale(&self) -> Duration {
-        max(self.stale_at() - Utc::now(), Duration::zero())
+        max(self.next_run_expected_at() - Utc::now(), Duration::zero())
     }
 
+    /// Whether a newer run is expected to be out, so the source is worth checking.
     pub fn is_stale(&self) -> bool {
         self.time_until_stale() == Duration::zero()
     }
 
-    pub fn get_at_coords(&self, lon: &f32, lat: &f32) -> Vec<Pollen> {
+    /// Whether there is a forecast for the location.
+    pub fn covers(&self, lon: &f32, lat: &f32) -> bool {
+        let (projected_lon, projected_lat) = project_lon_lat(lon, lat);
+        self.covers_projected(projected_lon, projected_lat)
+    }
+
+    pub fn get_at_coords(
+        &self,
+        lon: &f32,
+        lat: &f32,
+        interpolation: Interpolation,
+    ) -> Result<Vec<Pollen>, AppError> {
         let (projected_lon, projected_lat) = project_lon_lat(lon, lat);
+        if !self.covers_projected(projected_lon, projected_lat) {
+            return Err(AppError::OutsideCoverage);
+        }
+        let cells = self.cells_around(projected_lon, projected_lat, interpolation);
+
+        Ok((0..self.poli.shape()[0])
+            .map(|i| {
+                let time = self.start_time + Duration::hours(i.try_into().unwrap());
+                match interpolation {
+                    Interpolation::Nearest | Interpolation::Bilinear => {
+                        self.weighted_sample_at(time, i, &cells)
+                    }
+                    Interpolation::Max => self.max_sample_at(time, i, &cells),
+                }
+            })
+            .collect())
+    }
+
+    /// Hourly values over the window, which must be within the loaded data.
+    pub fn get_window(
+        &self,
+        lon: &f32,
+        lat: &f32,
+        interpolation: Interpolation,
+        window: &ForecastWindow,
+    ) -> Result<Vec<Pollen>, AppError> {
+        let pollen = self.get_at_coords(lon, lat, interpolation)?;
+        if window.start < self.start_time {
+            return Err(AppError::DataUnavailable);
+        }
+        let start_index = usize::try_from((window.start - self.start_time).num_hours())
+            .map_err(|_| AppError::DataUnavailable)?;
+        pollen
+            .get(start_index..start_index + window.hours)
+            .map(<[Pollen]>::to_vec)
+            .ok_or(AppError::DataUnavailable)
+    }
+
+    fn covers_projected(&self, projected_lon: f32, projected_lat: f32) -> bool {
+        let within = |vec: &[f32], target: f32| match (vec.first(), vec.last()) {
+            (Some(first), Some(last)) => first.min(*last) <= target && target <= first.max(*last),
+            _ => false,
+        };
+        if !within(&self.rlons, projected_lon) || !within(&self.rlats, projected_lat) {
+            return false;
+        }
 
-        let closest_rlon_index = find_closest(&self.rlons, projected_lon).unwrap();
-        let closest_rlat_index = find_closest(&self.rlats, projected_lat).unwrap();
+        // cells masked out of the model have fill values at every step
+        let rlat_index = find_closest(&self.rlats, projected_lat).unwrap();
+        let rlon_index = find_closest(&self.rlons, projected_lon).unwrap();
+        self.poli
+            .slice(s![.., rlat_index, rlon_index])
+            .iter()
+            .any(|raw| is_poli(*raw))
+    }
 
-        let pollen_indexes = self
-            .poli
-            .slice(s![.., closest_rlat_index, closest_rlon_index]); // apparently index here works by lat/lon, not lon/lat!
+    /// Grid cells (rlat index, rlon index, weight) to sample for a projected point.
+    fn cells_around(
+        &self,
+        projected_lon: f32,
+        projected_lat: f32,
+        interpolation: Interpolation,
+    ) -> Vec<(usize, usize, f32)> {
+        // apparently index here works by lat/lon, not lon/lat!
+        match interpolation {
+            Interpolation::Nearest => vec![(
+                find_closest(&self.rlats, projected_lat).unwrap(),
+                find_closest(&self.rlons, projected_lon).unwrap(),
+                1.0,
+            )],
+            Interpolation::Bilinear => {
+                let (rlat_0, rlat_1, t_lat) = find_bracket(&self.rlats, projected_lat);
+                let (rlon_0, rlon_1, t_lon) = find_bracket(&self.rlons, projected_lon);
+                vec![
+                    (rlat_0, rlon_0, (1.0 - t_lat) * (1.0 - t_lon)),
+                    (rlat_0, rlon_1, (1.0 - t_lat) * t_lon),
+                    (rlat_1, rlon_0, t_lat * (1.0 - t_lon)),
+                    (rlat_1, rlon_1, t_lat * t_lon),
+                ]
+            }
+            Interpolation::Max => {
+                let rlat = find_closest(&self.rlats, projected_lat).unwrap();
+                let rlon = find_closest(&self.rlons, projected_lon).unwrap();
+                // closest cell first so that it wins ties
+                let mut cells = vec![(rlat, rlon, 1.0)];
+                for rlat_n in rlat.saturating_sub(1)..=min(rlat + 1, self.rlats.len() - 1) {
+                    for rlon_n in rlon.saturating_sub(1)..=min(rlon + 1, self.rlons.len() - 1) {
+                        if (rlat_n, rlon_n) != (rlat, rlon) {
+                            cells.push((rlat_n, rlon_n, 1.0));
+                        }
+                    }
+                }
+                cells
+            }
+        }
+    }
 
-        pollen_indexes
+    /// Index is the weighted sum of raw POLI rounded to the nearest level, the source is taken
+    /// from the cell contributing most to that sum, and concentrations are weighted sums. Cells
+    /// masked out of the model are left out and the weights of the rest scaled up to make up for
+    /// them, so points near coasts and the edge of the domain aren't pulled towards zero.
+    fn weighted_sample_at(
+        &self,
+        time: DateTime<Utc>,
+        time_index: usize,
+        cells: &[(usize, usize, f32)],
+    ) -> Pollen {
+        let unmasked: Vec<(usize, usize, f32, f32)> = cells
             .iter()
-            .enumerate()
-            .map(|(i, pollen_index)| Pollen {
-                pollen_index: PollenIndex::from_raw(pollen_index),
-                pollen_index_source: PollenType::from_raw(
-                    self.polisrc
-                        .get((i, closest_rlat_index, closest_rlon_index))
-                        .unwrap(),
-                ),
-                time: self.start_time + Duration::hours(i.try_into().unwrap()),
+            .filter_map(|&(rlat_index, rlon_index, weight)| {
+                let raw = self.poli_at(time_index, rlat_index, rlon_index)?;
+                Some((rlat_index, rlon_index, weight, raw))
             })
-            .collect()
+            .collect();
+        if unmasked.is_empty() {
+            return Pollen::unknown(time);
+        }
+        let total_weight: f32 = unmasked.iter().map(|&(_, _, weight, _)| weight).sum();
+
+        let mut poli = 0.0;
+        let mut main_contribution = f32::MIN;
+        let mut source = PollenType::Unknown;
+        let mut concentrations = Concentrations::default();
+
+        for &(rlat_index, rlon_index, weight, raw) in &unmasked {
+            // a point on the grid line of a masked cell leaves the rest no weight to scale up
+            let weight = if total_weight > 0.0 {
+                weight / total_weight
+            } else {
+                1.0 / unmasked.len() as f32
+            };
+            let contribution = weight * raw;
+            poli += contribution;
+            if contribution > main_contribution {
+                main_contribution = contribution;
+                source = PollenType::from_raw(&self.polisrc[[time_index, rlat_index, rlon_index]]);
+            }
+            concentrations.combine(
+                &self.concentrations_at(time_index, rlat_index, rlon_index),
+                |acc, value| acc + weight * value,
+            );
+        }
+
+        Pollen {
+            time,
+            pollen_index: PollenIndex::from_raw(&poli.round()),
+            pollen_index_source: source,
+            concentrations,
+        }
     }
+
+    /// Index is the highest level among the cells, the source is taken from the first cell at
+    /// that level, and concentrations are per-species maxima. Masked cells are left out.
+    fn max_sample_at(
+        &self,
+        time: DateTime<Utc>,
+        time_index: usize,
+        cells: &[(usize, usize, f32)],
+    ) -> Pollen {
+        let mut poli = f32::MIN;
+        let mut source = PollenType::Unknown;
+        let mut concentrations = Concentrations::default();
+
+        for &(rlat_index, rlon_index, _) in cells {
+            let Some(raw) = self.poli_at(time_index, rlat_index, rlon_index) else {
+                continue;
+            };
+            if raw > poli {
+                poli = raw;
+                source = PollenType::from_raw(&self.polisrc[[time_index, rlat_index, rlon_index]]);
+            }
+            concentrations.combine(
+                &self.concentrations_at(time_index, rlat_index, rlon_index),
+                f32::max,
+            );
+        }
+
+        Pollen {
+            time,
+            pollen_index: PollenIndex::from_raw(&poli),
+            pollen_index_source: source,
+            concentrations,
+        }
+    }
+
+    /// Raw POLI of a cell, None where it is masked out of the model.
+    fn poli_at(&self, time_index: usize, rlat_index: usize, rlon_index: usize) -> Option<f32> {
+        let raw = self.poli[[time_index, rlat_index, rlon_index]];
+        is_poli(raw).then_some(raw)
+    }
+
+    fn concentrations_at(
+        &self,
+        time_index: usize,
+        rlat_index: usize,
+        rlon_index: usize,
+    ) -> Concentrations {
+        let mut concentrations = Concentrations::default();
+        for (species, cnc) in PollenType::SPECIES.iter().zip(&self.cnc) {
+            let value = *cnc.get((time_index, rlat_index, rlon_index)).unwrap();
+            concentrations.set(*species, value.max(0.0));
+        }
+        concentrations
+    }
+}
+
+fn read_array3(file: &netcdf::File, name: &str) -> Result<Array3<f32>, Box<dyn std::error::Error>> {
+    let array = file
+        .variable(name)
+        .ok_or(format!("{} variable missing", name))?
+        .get::<f32, _>(..)?
+        .into_dimensionality::<Ix3>()
+        .map_err(|_| format!("{} could not be parsed as Array3", name))?;
+    Ok(array)
+}
+
+/// Time of the first step, from the `time` variable.
+fn read_start_time(file: &netcdf::File) -> Result<DateTime<Utc>, Box<dyn std::error::Error>> {
+    let time = file.variable("time").ok_or("time variable missing")?;
+    read_times(&time)?
+        .first()
+        .copied()
+        .ok_or_else(|| "time variable empty".into())
+}
+
+/// Model run time, from the `_CoordinateModelRunDate` attribute THREDDS adds to single runs, or
+/// the latest forecast reference time of a "best" time series stitched together from several.
+fn read_run_time(file: &netcdf::File) -> Option<DateTime<Utc>> {
+    let run_date = match file
+        .attribute("_CoordinateModelRunDate")
+        .and_then(|attribute| attribute.value().ok())
+    {
+        Some(AttributeValue::Str(run_date)) => Some(run_date),
+        _ => None,
+    };
+    let reference_times = file
+        .variables()
+        .filter(|variable| {
+            matches!(
+                variable.attribute_value("standard_name"),
+                Some(Ok(AttributeValue::Str(name))) if name == "forecast_reference_time"
+            )
+        })
+        .find_map(|variable| read_times(&variable).ok());
+    run_time_from(run_date.as_deref(), reference_times.as_deref())
+}
+
+fn run_time_from(
+    run_date: Option<&str>,
+    reference_times: Option<&[DateTime<Utc>]>,
+) -> Option<DateTime<Utc>> {
+    run_date
+        .and_then(|run_date| DateTime::parse_from_rfc3339(run_date).ok())
+        .map(|run_time| run_time.to_utc())
+        .or_else(|| reference_times?.iter().max().copied())
+}
+
+/// Values of a time variable, using its CF-style units.
+fn read_times(
+    variable: &netcdf::Variable,
+) -> Result<Vec<DateTime<Utc>>, Box<dyn std::error::Error>> {
+    let name = variable.name();
+    let units = match variable.attribute_value("units").transpose()? {
+        Some(AttributeValue::Str(units)) => units,
+        _ => return Err(format!("{} units missing", name).into()),
+    };
+    let (epoch, seconds_per_unit) =
+        parse_time_units(&units).map_err(|err| format!("{} {}", name, err))?;
+
+    Ok(variable
+        .get_values::<f64, _>(..)?
+        .into_iter()
+        .map(|value| epoch + Duration::seconds((value * seconds_per_unit) as i64))
+        .collect())
+}
+
+/// The epoch and seconds per unit of "<unit> since <epoch>" time units.
+fn parse_time_units(units: &str) -> Result<(DateTime<Utc>, f64), String> {
+    let (unit, epoch) = units
+        .split_once(" since ")
+        .ok_or(format!("units not understood: {}", units))?;
+    let epoch = epoch
+        .trim()
+        .trim_end_matches("UTC")
+        .trim_end_matches('Z')
+        .trim()
+        .replace('T', " ");
+    let epoch = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(&epoch, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(&epoch, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+        .ok_or(format!("epoch not understood: {}", epoch))?
+        .and_utc();
+    let seconds_per_unit = match unit.trim() {
+        "days" => 86400.0,
+        "hours" => 3600.0,
+        "minutes" => 60.0,
+        "seconds" => 1.0,
+        other => return Err(format!("unit not understood: {}", other)),
+    };
+    Ok((epoch, seconds_per_unit))
+}
+
+/// Run times from a THREDDS runs catalog, which lists datasets named `..._RUN_<rfc3339>`.
+fn parse_run_times(catalog: &str) -> Vec<DateTime<Utc>> {
+    catalog
+        .split("_RUN_")
+        .skip(1)
+        .filter_map(|rest| rest.get(.."2000-01-01T00:00:00Z".len()))
+        .filter_map(|run_date| DateTime::parse_from_rfc3339(run_date).ok())
+        .map(|run_time| run_time.to_utc())
+        .collect()
 }
 
 fn project_lon_lat(lon: &f32, lat: &f32) -> (f32, f32) {
@@ -247,3 +756,249 @@ fn find_closest(vec: &Vec<f32>, target: f32) -> Option<usize> {
         }
     }
 }
+
+/// Whether a raw POLI value is an index level rather than a fill value for a masked cell.
+fn is_poli(raw: f32) -> bool {
+    raw.is_finite() && (0.0..=5.0).contains(&raw)
+}
+
+/// Indexes of the two elements either side of the target and how far between them it lies.
+fn find_bracket(vec: &[f32], target: f32) -> (usize, usize, f32) {
+    let upper = vec.partition_point(|probe| *probe < target);
+    if upper == 0 {
+        (0, 0, 0.0)
+    } else if upper == vec.len() {
+        (vec.len() - 1, vec.len() - 1, 0.0)
+    } else {
+        let lower = upper - 1;
+        let t = (target - vec[lower]) / (vec[upper] - vec[lower]);
+        (lower, upper, t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().to_utc()
+    }
+
+    /// A 3x3 grid at whole projected degrees with one step of `poli`, birch everywhere, and
+    /// concentrations ten times the index.
+    fn grid(poli: [[f32; 3]; 3]) -> Silam {
+        let time = utc("2024-03-01T00:00:00Z");
+        let poli = Array3::from_shape_fn((1, 3, 3), |(_, rlat, rlon)| poli[rlat][rlon]);
+        Silam {
+            fetch_time: time,
+            start_time: time,
+            run_time: time,
+            cnc: PollenType::SPECIES
+                .iter()
+                .filter(|species| species.silam_variable().is_some())
+                .map(|_| poli.mapv(|raw| raw * 10.0))
+                .collect(),
+            polisrc: Array3::from_elem((1, 3, 3), 2.0),
+            poli,
+            rlats: vec![0.0, 1.0, 2.0],
+            rlons: vec![0.0, 1.0, 2.0],
+        }
+    }
+
+    /// The index and birch concentration, to three decimal places, at a projected point.
+    fn sample(
+        silam: &Silam,
+        (rlon, rlat): (f32, f32),
+        interpolation: Interpolation,
+    ) -> (PollenIndex, f32) {
+        let cells = silam.cells_around(rlon, rlat, interpolation);
+        let pollen = match interpolation {
+            Interpolation::Max => silam.max_sample_at(silam.start_time, 0, &cells),
+            _ => silam.weighted_sample_at(silam.start_time, 0, &cells),
+        };
+        let birch = (pollen.concentrations.birch * 1000.0).round() / 1000.0;
+        (pollen.pollen_index, birch)
+    }
+
+    #[test]
+    fn brackets_targets() {
+        let grid = [0.0, 1.0, 2.0, 3.0];
+        assert_eq!(find_bracket(&grid, 1.25), (1, 2, 0.25));
+        // on a grid point, the whole weight goes to it
+        assert_eq!(find_bracket(&grid, 2.0), (1, 2, 1.0));
+        assert_eq!(find_bracket(&grid, 0.0), (0, 0, 0.0));
+        assert_eq!(find_bracket(&grid, 3.0), (2, 3, 1.0));
+        // outside, clamped to the edge
+        assert_eq!(find_bracket(&grid, -1.0), (0, 0, 0.0));
+        assert_eq!(find_bracket(&grid, 4.0), (3, 3, 0.0));
+    }
+
+    #[test]
+    fn samples_nearest_cell() {
+        let silam = grid([[1.0, 2.0, 3.0], [2.0, 3.0, 4.0], [3.0, 4.0, 5.0]]);
+        assert_eq!(
+            sample(&silam, (0.4, 0.4), Interpolation::Nearest),
+            (PollenIndex::VeryLow, 10.0)
+        );
+        assert_eq!(
+            sample(&silam, (1.4, 0.4), Interpolation::Nearest),
+            (PollenIndex::Low, 20.0)
+        );
+        assert_eq!(
+            sample(&silam, (2.0, 2.0), Interpolation::Nearest),
+            (PollenIndex::VeryHigh, 50.0)
+        );
+        // masked cells have no value rather than a zero one
+        let silam = grid([[f32::NAN, 2.0, 3.0], [2.0, 3.0, 4.0], [3.0, 4.0, 5.0]]);
+        assert_eq!(
+            sample(&silam, (0.0, 0.0), Interpolation::Nearest),
+            (PollenIndex::Unknown, 0.0)
+        );
+    }
+
+    #[test]
+    fn samples_bilinear() {
+        let silam = grid([[1.0, 3.0, 3.0], [3.0, 5.0, 5.0], [3.0, 5.0, 5.0]]);
+        assert_eq!(
+            sample(&silam, (0.5, 0.5), Interpolation::Bilinear),
+            (PollenIndex::Moderate, 30.0)
+        );
+        assert_eq!(
+            sample(&silam, (0.25, 0.0), Interpolation::Bilinear),
+            (PollenIndex::Low, 15.0)
+        );
+        // on grid points and the edge of the grid
+        assert_eq!(
+            sample(&silam, (1.0, 1.0), Interpolation::Bilinear),
+            (PollenIndex::VeryHigh, 50.0)
+        );
+        assert_eq!(
+            sample(&silam, (0.0, 0.0), Interpolation::Bilinear),
+            (PollenIndex::VeryLow, 10.0)
+        );
+        assert_eq!(
+            sample(&silam, (2.0, 2.0), Interpolation::Bilinear),
+            (PollenIndex::VeryHigh, 50.0)
+        );
+    }
+
+    #[test]
+    fn bilinear_leaves_out_masked_cells() {
+        let silam = grid([[4.0, 4.0, 4.0], [f32::NAN, 4.0, 4.0], [4.0, 4.0, 4.0]]);
+        // rather than 3 and 30 with the masked corner taken as zero
+        assert_eq!(
+            sample(&silam, (0.5, 0.5), Interpolation::Bilinear),
+            (PollenIndex::High, 40.0)
+        );
+        // a point on the masked cell shares the weight among the others
+        assert_eq!(
+            sample(&silam, (0.0, 1.0), Interpolation::Bilinear),
+            (PollenIndex::High, 40.0)
+        );
+
+        let nan = f32::NAN;
+        let silam = grid([[nan, nan, 4.0], [nan, nan, 4.0], [4.0, 4.0, 4.0]]);
+        assert_eq!(
+            sample(&silam, (0.5, 0.5), Interpolation::Bilinear),
+            (PollenIndex::Unknown, 0.0)
+        );
+        // fill values outside the index range are masked too
+        let silam = grid([[1e20, 2.0, 2.0], [2.0, 2.0, 2.0], [2.0, 2.0, 2.0]]);
+        assert_eq!(
+            sample(&silam, (0.5, 0.5), Interpolation::Bilinear).0,
+            PollenIndex::Low
+        );
+    }
+
+    #[test]
+    fn samples_max_of_neighbours() {
+        let silam = grid([[1.0, 2.0, f32::NAN], [2.0, 3.0, 2.0], [1.0, 1.0, 5.0]]);
+        assert_eq!(
+            sample(&silam, (0.2, 0.2), Interpolation::Max),
+            (PollenIndex::Moderate, 30.0)
+        );
+        assert_eq!(
+            sample(&silam, (1.0, 1.0), Interpolation::Max),
+            (PollenIndex::VeryHigh, 50.0)
+        );
+        let nan = f32::NAN;
+        let silam = grid([[nan, nan, nan], [nan, nan, nan], [nan, nan, 5.0]]);
+        assert_eq!(
+            sample(&silam, (0.0, 0.0), Interpolation::Max),
+            (PollenIndex::Unknown, 0.0)
+        );
+    }
+
+    #[test]
+    fn parses_time_units() {
+        for (units, epoch, seconds_per_unit) in [
+            (
+                "hours since 2024-03-01 00:00:00",
+                "2024-03-01T00:00:00Z",
+                3600.0,
+            ),
+            (
+                "hours since 2024-03-01T06:00:00Z",
+                "2024-03-01T06:00:00Z",
+                3600.0,
+            ),
+            ("days since 2024-03-01", "2024-03-01T00:00:00Z", 86400.0),
+            (
+                "minutes since 2024-03-01 12:30 UTC",
+                "2024-03-01T12:30:00Z",
+                60.0,
+            ),
+            (
+                "seconds since 1970-01-01 00:00:00",
+                "1970-01-01T00:00:00Z",
+                1.0,
+            ),
+        ] {
+            assert_eq!(
+                parse_time_units(units).unwrap(),
+                (utc(epoch), seconds_per_unit),
+                "{}",
+                units
+            );
+        }
+        assert!(parse_time_units("hours").is_err());
+        assert!(parse_time_units("fortnights since 2024-03-01").is_err());
+        assert!(parse_time_units("hours since yesterday").is_err());
+    }
+
+    #[test]
+    fn reads_run_time() {
+        let reference_times = [
+            utc("2024-03-01T00:00:00Z"),
+            utc("2024-03-02T00:00:00Z"),
+            utc("2024-03-01T12:00:00Z"),
+        ];
+        // the attribute of a single run wins
+        assert_eq!(
+            run_time_from(Some("2024-03-03T00:00:00Z"), Some(&reference_times)),
+            Some(utc("2024-03-03T00:00:00Z"))
+        );
+        // a best time series is as recent as the latest run in it
+        assert_eq!(
+            run_time_from(None, Some(&reference_times)),
+            Some(utc("2024-03-02T00:00:00Z"))
+        );
+        assert_eq!(
+            run_time_from(Some("not a date"), Some(&reference_times)),
+            Some(utc("2024-03-02T00:00:00Z"))
+        );
+        assert_eq!(run_time_from(None, Some(&[])), None);
+        assert_eq!(run_time_from(None, None), None);
+    }
+
+    #[test]
+    fn parses_catalog_run_times() {
+        let catalog = r#"<dataset name="silam_europe_pollen_v6_0_RUN_2024-03-01T00:00:00Z" urlPath="silam_europe_pollen_v6_0/runs/silam_europe_pollen_v6_0_RUN_2024-03-01T00:00:00Z"/>
+            <dataset name="silam_europe_pollen_v6_0_RUN_2024-03-02T00:00:00Z"/>"#;
+        assert_eq!(
+            parse_run_times(catalog).into_iter().max(),
+            Some(utc("2024-03-02T00:00:00Z"))
+        );
+        assert!(parse_run_times("<catalog/>").is_empty());
+    }
+}
@@ -0,0 +1,192 @@
+use chrono::{DateTime, Utc};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use crate::{metrics::METRICS, silam::Silam};
+
+/// Where SILAM data is loaded from, configured by the `SILAM_SOURCE` secret.
+pub enum SilamSource {
+    /// Download from FMI's THREDDS server, keeping the last good download in `cache` if set.
+    Thredds {
+        email: Option<String>,
+        cache: Option<SilamCache>,
+    },
+    /// A single NetCDF file.
+    File(PathBuf),
+    /// The most recently modified NetCDF file in a directory.
+    Directory(PathBuf),
+}
+
+impl SilamSource {
+    /// `source` is either unset or `thredds`, or a path to a file or directory.
+    pub fn from_config(
+        source: Option<String>,
+        silam_email: Option<String>,
+        cache_dir: Option<String>,
+    ) -> SilamSource {
+        let thredds = || SilamSource::Thredds {
+            email: silam_email.clone(),
+            cache: cache_dir
+                .clone()
+                .map(|dir| SilamCache::new(PathBuf::from(dir))),
+        };
+        match source {
+            None => thredds(),
+            Some(source) if source == "thredds" => thredds(),
+            Some(path) => {
+                let path = PathBuf::from(path);
+                if path.is_dir() {
+                    SilamSource::Directory(path)
+                } else {
+                    SilamSource::File(path)
+                }
+            }
+        }
+    }
+
+    /// Data from the last good download, if this source keeps one and it can be read.
+    pub fn load_cached(&self) -> Option<Silam> {
+        match self {
+            SilamSource::Thredds {
+                cache: Some(cache), ..
+            } => match cache.load() {
+                Ok(silam) => silam,
+                Err(err) => {
+                    println!("Failed to load cached SILAM data: {}", err);
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
+
+    /// Whether checking for newer data means a request to another server, rather than looking
+    /// at a file's modified time.
+    pub fn is_remote(&self) -> bool {
+        matches!(self, SilamSource::Thredds { .. })
+    }
+
+    /// Whether the source has data newer than `current` without loading it: a later model run
+    /// on THREDDS, or a file modified since it was loaded.
+    pub async fn has_newer(
+        &self,
+        current: &SilamVersion,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        match self {
+            SilamSource::Thredds { .. } => {
+                Ok(Silam::fetch_latest_run_time().await? > current.run_time)
+            }
+            SilamSource::File(path) => Ok(modified_time(path)? > current.fetch_time),
+            SilamSource::Directory(dir) => {
+                Ok(modified_time(&newest_netcdf_file(dir)?)? > current.fetch_time)
+            }
+        }
+    }
+
+    pub async fn load(&self) -> Result<Silam, Box<dyn std::error::Error>> {
+        match self {
+            SilamSource::Thredds { email, cache } => {
+                // only needed if the download doesn't say which run it is from, so a failure
+                // here shouldn't stop the download
+                let polled_run_time = Silam::fetch_latest_run_time()
+                    .await
+                    .inspect_err(|err| println!("Failed to check THREDDS catalog: {}", err))
+                    .ok();
+                let started = Instant::now();
+                let (silam, bytes) = Silam::fetch(email, polled_run_time).await?;
+                METRICS.record_fetch(started.elapsed(), bytes.len());
+                if let Some(cache) = cache {
+                    if let Err(err) = cache.store(&bytes) {
+                        println!("Failed to cache SILAM data: {}", err);
+                    }
+                }
+                Ok(silam)
+            }
+            SilamSource::File(path) => Silam::open(path),
+            SilamSource::Directory(dir) => Silam::open(&newest_netcdf_file(dir)?),
+        }
+    }
+}
+
+/// Identifies which data is loaded, for comparing against what a source has available.
+pub struct SilamVersion {
+    pub fetch_time: DateTime<Utc>,
+    pub run_time: DateTime<Utc>,
+}
+
+impl From<&Silam> for SilamVersion {
+    fn from(silam: &Silam) -> SilamVersion {
+        SilamVersion {
+            fetch_time: silam.fetch_time,
+            run_time: silam.run_time,
+        }
+    }
+}
+
+/// The last good THREDDS download, stored as the raw NetCDF. Its times are read back from the
+/// file itself like any other, so there is nothing alongside it to get out of step.
+pub struct SilamCache {
+    dir: PathBuf,
+}
+
+impl SilamCache {
+    pub fn new(dir: PathBuf) -> SilamCache {
+        SilamCache { dir }
+    }
+
+    fn data_path(&self) -> PathBuf {
+        self.dir.join("silam.nc")
+    }
+
+    fn load(&self) -> Result<Option<Silam>, Box<dyn std::error::Error>> {
+        if !self.data_path().exists() {
+            return Ok(None);
+        }
+        // written once downloaded, so its modified time is the fetch time
+        Ok(Some(Silam::open(&self.data_path())?))
+    }
+
+    fn store(&self, bytes: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        fs::create_dir_all(&self.dir)?;
+        // write then rename so a crash mid-write never leaves a truncated cache behind
+        write_atomic(&self.data_path(), bytes)?;
+        Ok(())
+    }
+}
+
+pub fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, contents)?;
+    fs::rename(tmp_path, path)
+}
+
+fn modified_time(path: &Path) -> std::io::Result<DateTime<Utc>> {
+    Ok(fs::metadata(path)?.modified()?.into())
+}
+
+fn newest_netcdf_file(dir: &Path) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let mut newest = None;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_netcdf = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| matches!(extension, "nc" | "nc4" | "ncd"));
+        if !is_netcdf {
+            continue;
+        }
+        let modified = modified_time(&path)?;
+        if newest
+            .as_ref()
+            .is_none_or(|(newest_modified, _)| modified > *newest_modified)
+        {
+            newest = Some((modified, path));
+        }
+    }
+    newest
+        .map(|(_, path)| path)
+        .ok_or_else(|| format!("no NetCDF files in {}", dir.display()).into())
+}
@@ -0,0 +1,78 @@
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use crate::{error::AppError, source::write_atomic};
+
+/// A list kept in memory and saved as JSON in the data directory if one is configured, so it
+/// survives restarts.
+pub struct JsonStore<T> {
+    path: Option<PathBuf>,
+    items: RwLock<Vec<T>>,
+}
+
+impl<T: Serialize + DeserializeOwned + Clone> JsonStore<T> {
+    pub fn open(
+        data_dir: Option<&Path>,
+        file_name: &str,
+    ) -> Result<JsonStore<T>, Box<dyn std::error::Error>> {
+        let path = data_dir.map(|dir| dir.join(file_name));
+        let items = match &path {
+            Some(path) if path.exists() => serde_json::from_slice(&fs::read(path)?)?,
+            _ => Vec::new(),
+        };
+        Ok(JsonStore {
+            path,
+            items: RwLock::new(items),
+        })
+    }
+
+    pub fn list(&self) -> Vec<T> {
+        self.items.read().unwrap().clone()
+    }
+
+    pub fn find(&self, predicate: impl Fn(&T) -> bool) -> Option<T> {
+        self.items
+            .read()
+            .unwrap()
+            .iter()
+            .find(|item| predicate(item))
+            .cloned()
+    }
+
+    /// Applies `change` and saves, leaving the list as it was if either fails.
+    pub fn update<R>(
+        &self,
+        change: impl FnOnce(&mut Vec<T>) -> Result<R, AppError>,
+    ) -> Result<R, AppError> {
+        let mut items = self.items.write().unwrap();
+        let previous = items.clone();
+        let result = change(&mut items).and_then(|result| {
+            self.save(&items)?;
+            Ok(result)
+        });
+        if result.is_err() {
+            *items = previous;
+        }
+        result
+    }
+
+    fn save(&self, items: &[T]) -> Result<(), AppError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let save = || -> Result<(), Box<dyn std::error::Error>> {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            write_atomic(path, &serde_json::to_vec_pretty(items)?)?;
+            Ok(())
+        };
+        save().map_err(|err| {
+            AppError::Internal(format!("failed to save {}: {}", path.display(), err))
+        })
+    }
+}
@@ -0,0 +1,471 @@
+use chrono::{DateTime, Utc};
+use reqwest::header;
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::min,
+    collections::HashSet,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time;
+
+use crate::{
+    daily::{periods, Period},
+    error::AppError,
+    outbound,
+    secret::{random_hex, sign},
+    silam::{Interpolation, Pollen, PollenIndex, PollenType, Silam},
+    store::JsonStore,
+    AppState,
+};
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Backoff doubles from 30 seconds, so the last attempt is about an hour after the first.
+const MAX_ATTEMPTS: u32 = 8;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+/// Registrations kept in all.
+const MAX_WEBHOOKS: usize = 10_000;
+/// Registrations for one URL, which is usually one receiver.
+const MAX_WEBHOOKS_PER_URL: usize = 20;
+
+/// Header carrying the hex HMAC-SHA256 of the body, keyed with the webhook's secret.
+pub const SIGNATURE_HEADER: &str = "X-Pollen-Signature";
+
+/// A registration to be POSTed an alert when pollen at a location is forecast to reach
+/// `threshold`, counting only hours `species` is the main source of if it is set.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Webhook {
+    pub id: String,
+    /// Key payloads are signed with, only shown to the client when registering.
+    pub secret: String,
+    pub url: String,
+    pub lon: f32,
+    pub lat: f32,
+    pub interpolation: Interpolation,
+    pub threshold: PollenIndex,
+    pub species: Option<PollenType>,
+    pub created: DateTime<Utc>,
+    /// End of the last period alerted on, so each crossing is only sent once.
+    pub alerted_until: Option<DateTime<Utc>>,
+}
+
+impl Webhook {
+    fn is_alerting(&self, pollen: &Pollen) -> bool {
+        pollen.pollen_index >= self.threshold
+            && self
+                .species
+                .is_none_or(|species| pollen.pollen_index_source == species)
+    }
+
+    fn is_same_as(&self, new: &NewWebhook) -> bool {
+        self.url == new.url
+            && self.lon == new.lon
+            && self.lat == new.lat
+            && self.interpolation == new.interpolation
+            && self.threshold == new.threshold
+            && self.species == new.species
+    }
+}
+
+/// A checked request to register a webhook.
+pub struct NewWebhook {
+    pub url: String,
+    pub lon: f32,
+    pub lat: f32,
+    pub interpolation: Interpolation,
+    pub threshold: PollenIndex,
+    pub species: Option<PollenType>,
+}
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    /// The same for every delivery of one alert, for receivers to drop duplicates.
+    event_id: &'a str,
+    webhook_id: &'a str,
+    location: &'a str,
+    lon: f32,
+    lat: f32,
+    threshold: PollenIndex,
+    species: Option<PollenType>,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    max_index: PollenIndex,
+    source: PollenType,
+    fetch_time: DateTime<Utc>,
+    run_time: DateTime<Utc>,
+    attribution: &'a str,
+}
+
+/// Registered webhooks, kept in `webhooks.json` in the data directory if one is configured.
+pub struct WebhookStore {
+    webhooks: JsonStore<Webhook>,
+    /// Event ids being delivered, so a refresh during retries doesn't send them again.
+    in_flight: Mutex<HashSet<String>>,
+    /// Whether URLs may point at loopback and private addresses, for testing against a local
+    /// receiver.
+    allow_local_targets: bool,
+    initial_backoff: Duration,
+}
+
+impl WebhookStore {
+    pub fn open(
+        data_dir: Option<&Path>,
+        allow_local_targets: bool,
+    ) -> Result<WebhookStore, Box<dyn std::error::Error>> {
+        Ok(WebhookStore {
+            webhooks: JsonStore::open(data_dir, "webhooks.json")?,
+            in_flight: Mutex::new(HashSet::new()),
+            allow_local_targets,
+            initial_backoff: INITIAL_BACKOFF,
+        })
+    }
+
+    /// Registers the webhook, or returns the existing one with the same settings. The flag is
+    /// whether it was created. URLs must resolve to public addresses.
+    pub async fn register(&self, new: NewWebhook) -> Result<(Webhook, bool), AppError> {
+        let url = reqwest::Url::parse(&new.url)
+            .map_err(|err| AppError::InvalidBody(format!("url is not valid: {}", err)))?;
+        outbound::resolve_public(&url, self.allow_local_targets)
+            .await
+            .map_err(|err| AppError::InvalidBody(format!("url can't be used: {}", err)))?;
+
+        self.webhooks.update(|webhooks| {
+            if let Some(existing) = webhooks.iter().find(|webhook| webhook.is_same_as(&new)) {
+                return Ok((existing.clone(), false));
+            }
+            let for_url = webhooks
+                .iter()
+                .filter(|webhook| webhook.url == new.url)
+                .count();
+            if webhooks.len() >= MAX_WEBHOOKS || for_url >= MAX_WEBHOOKS_PER_URL {
+                return Err(AppError::TooManySubscriptions);
+            }
+
+            let webhook = Webhook {
+                id: random_hex(16)?,
+                secret: random_hex(32)?,
+                url: new.url,
+                lon: new.lon,
+                lat: new.lat,
+                interpolation: new.interpolation,
+                threshold: new.threshold,
+                species: new.species,
+                created: Utc::now(),
+                alerted_until: None,
+            };
+            webhooks.push(webhook.clone());
+            Ok((webhook, true))
+        })
+    }
+
+    pub fn get(&self, id: &str) -> Option<Webhook> {
+        self.webhooks.find(|webhook| webhook.id == id)
+    }
+
+    pub fn delete(&self, id: &str) -> Result<(), AppError> {
+        self.webhooks.update(|webhooks| {
+            let index = webhooks
+                .iter()
+                .position(|webhook| webhook.id == id)
+                .ok_or(AppError::SubscriptionNotFound)?;
+            webhooks.remove(index);
+            Ok(())
+        })
+    }
+
+    fn mark_alerted(&self, id: &str, until: DateTime<Utc>) -> Result<(), AppError> {
+        self.webhooks.update(|webhooks| {
+            if let Some(webhook) = webhooks.iter_mut().find(|webhook| webhook.id == id) {
+                webhook.alerted_until = Some(until);
+            }
+            Ok(())
+        })
+    }
+
+    /// The first upcoming period at or above the webhook's threshold that it hasn't been
+    /// alerted on, with its event id. The event is claimed until `deliver` finishes with it, so
+    /// it isn't sent twice at once.
+    fn claim_alert<'a>(
+        &self,
+        webhook: &Webhook,
+        upcoming: &'a [Pollen],
+    ) -> Option<(String, Period<'a>)> {
+        let period = periods(upcoming, |pollen| webhook.is_alerting(pollen))
+            .into_iter()
+            .find(|period| {
+                webhook
+                    .alerted_until
+                    .is_none_or(|until| period.start() >= until)
+            })?;
+        let event_id = format!("{}-{}", webhook.id, period.start().timestamp());
+        self.in_flight
+            .lock()
+            .unwrap()
+            .insert(event_id.clone())
+            .then_some((event_id, period))
+    }
+
+    async fn send(&self, webhook: &Webhook, body: &[u8]) -> Result<(), String> {
+        let url = reqwest::Url::parse(&webhook.url).map_err(|err| err.to_string())?;
+        // checked again as the host may resolve somewhere else since it was registered
+        let client = outbound::client_for(&url, DELIVERY_TIMEOUT, self.allow_local_targets).await?;
+        let signature = sign(webhook.secret.as_bytes(), body);
+        client
+            .post(url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, format!("sha256={}", signature))
+            .body(body.to_vec())
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|err| err.to_string())?;
+        Ok(())
+    }
+
+    /// Sends the alert, retrying failures with exponential backoff until it is accepted, the
+    /// webhook is deleted or the attempts run out.
+    async fn deliver(&self, id: &str, event_id: &str, body: &[u8], alerted_until: DateTime<Utc>) {
+        let mut backoff = self.initial_backoff;
+        for attempt in 1..=MAX_ATTEMPTS {
+            let Some(webhook) = self.get(id) else {
+                break;
+            };
+            match self.send(&webhook, body).await {
+                Ok(()) => {
+                    if let Err(err) = self.mark_alerted(id, alerted_until) {
+                        println!("{}", err);
+                    }
+                    break;
+                }
+                Err(err) => {
+                    println!(
+                        "Failed to deliver webhook {} (attempt {}/{}): {}",
+                        id, attempt, MAX_ATTEMPTS, err
+                    );
+                }
+            }
+            if attempt == MAX_ATTEMPTS {
+                println!("Giving up on webhook {} event {}", id, event_id);
+            } else {
+                time::sleep(backoff).await;
+                backoff = min(backoff * 2, MAX_BACKOFF);
+            }
+        }
+        self.in_flight.lock().unwrap().remove(event_id);
+    }
+}
+
+/// Checks every webhook against the current data.
+pub async fn notify_webhooks(state: Arc<AppState>) {
+    let silam = state.silam.load_full();
+    for webhook in state.webhooks.webhooks.list() {
+        notify_webhook(&state, &silam, webhook);
+    }
+}
+
+/// Starts delivering an alert for the first upcoming period at or above the webhook's
+/// threshold that it hasn't been alerted on yet.
+pub fn notify_webhook(state: &Arc<AppState>, silam: &Silam, webhook: Webhook) {
+    let now = Utc::now();
+    let pollen = match silam.get_at_coords(&webhook.lon, &webhook.lat, webhook.interpolation) {
+        Ok(pollen) => pollen,
+        Err(err) => {
+            println!("Skipping webhook {}: {}", webhook.id, err);
+            return;
+        }
+    };
+    let upcoming: Vec<Pollen> = pollen
+        .into_iter()
+        .filter(|pollen| pollen.time + chrono::Duration::hours(1) > now)
+        .collect();
+    let Some((event_id, period)) = state.webhooks.claim_alert(&webhook, &upcoming) else {
+        return;
+    };
+
+    let location = state.location_name(webhook.lon, webhook.lat);
+    let payload = WebhookPayload {
+        event_id: &event_id,
+        webhook_id: &webhook.id,
+        location: &location,
+        lon: webhook.lon,
+        lat: webhook.lat,
+        threshold: webhook.threshold,
+        species: webhook.species,
+        start: period.start(),
+        end: period.end(),
+        max_index: period.max_index,
+        source: period.source,
+        fetch_time: silam.fetch_time,
+        run_time: silam.run_time,
+        attribution: "Data from FMI SILAM and EAN",
+    };
+    let body = serde_json::to_vec(&payload).expect("webhook payloads always serialize");
+
+    let alerted_until = period.end();
+    let state = Arc::clone(state);
+    tokio::spawn(async move {
+        state
+            .webhooks
+            .deliver(&webhook.id, &event_id, &body, alerted_until)
+            .await;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{secret::verify, silam::Concentrations};
+    use axum::{body::Bytes, http::HeaderMap, http::StatusCode, routing::post, Router};
+    use chrono::DurationRound;
+    use std::collections::VecDeque;
+    use tokio::net::TcpListener;
+    use PollenIndex::{High, Low};
+
+    type Received = Arc<Mutex<Vec<(HeaderMap, Bytes)>>>;
+
+    /// A local receiver answering with `statuses` in turn, then 200, and keeping what it was
+    /// sent.
+    async fn receiver(statuses: &[u16]) -> (String, Received) {
+        let received: Received = Arc::default();
+        let statuses = Arc::new(Mutex::new(
+            statuses.iter().copied().collect::<VecDeque<_>>(),
+        ));
+        let app = Router::new().route(
+            "/hook",
+            post({
+                let received = Arc::clone(&received);
+                move |headers: HeaderMap, body: Bytes| async move {
+                    received.lock().unwrap().push((headers, body));
+                    let status = statuses.lock().unwrap().pop_front().unwrap_or(200);
+                    StatusCode::from_u16(status).unwrap()
+                }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, received)
+    }
+
+    fn store() -> WebhookStore {
+        WebhookStore {
+            initial_backoff: Duration::from_millis(10),
+            ..WebhookStore::open(None, true).unwrap()
+        }
+    }
+
+    fn new_webhook(url: &str) -> NewWebhook {
+        NewWebhook {
+            url: url.to_string(),
+            lon: 24.94,
+            lat: 60.17,
+            interpolation: Interpolation::Nearest,
+            threshold: PollenIndex::High,
+            species: None,
+        }
+    }
+
+    /// Consecutive hours from the next whole hour.
+    fn hours(indexes: &[PollenIndex]) -> Vec<Pollen> {
+        let start = Utc::now()
+            .duration_trunc(chrono::Duration::hours(1))
+            .unwrap()
+            + chrono::Duration::hours(1);
+        indexes
+            .iter()
+            .enumerate()
+            .map(|(i, index)| Pollen {
+                time: start + chrono::Duration::hours(i as i64),
+                pollen_index: *index,
+                pollen_index_source: PollenType::Birch,
+                concentrations: Concentrations::default(),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn signs_deliveries() {
+        let (url, received) = receiver(&[]).await;
+        let store = store();
+        let (webhook, _) = store.register(new_webhook(&url)).await.unwrap();
+
+        let body = br#"{"event_id":"test"}"#;
+        store.deliver(&webhook.id, "test", body, Utc::now()).await;
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        let (headers, sent) = &received[0];
+        assert_eq!(sent.as_ref(), body);
+        let signature = headers[SIGNATURE_HEADER].to_str().unwrap();
+        let signature = signature.strip_prefix("sha256=").unwrap();
+        assert!(verify(webhook.secret.as_bytes(), body, signature));
+    }
+
+    #[tokio::test]
+    async fn alerts_each_period_once() {
+        let (url, received) = receiver(&[]).await;
+        let store = store();
+        let (webhook, _) = store.register(new_webhook(&url)).await.unwrap();
+        let upcoming = hours(&[Low, High, High, Low, High]);
+
+        let (event_id, period) = store.claim_alert(&webhook, &upcoming).unwrap();
+        assert_eq!(period.start(), upcoming[1].time);
+        // already being delivered
+        assert!(store.claim_alert(&webhook, &upcoming).is_none());
+
+        store
+            .deliver(&webhook.id, &event_id, event_id.as_bytes(), period.end())
+            .await;
+        assert_eq!(received.lock().unwrap().len(), 1);
+
+        // delivered, so the next refresh moves on to the following period
+        let webhook = store.get(&webhook.id).unwrap();
+        let (next_event_id, next_period) = store.claim_alert(&webhook, &upcoming).unwrap();
+        assert_ne!(next_event_id, event_id);
+        assert_eq!(next_period.start(), upcoming[4].time);
+    }
+
+    #[tokio::test]
+    async fn retries_after_server_error() {
+        let (url, received) = receiver(&[500, 500]).await;
+        let store = store();
+        let (webhook, _) = store.register(new_webhook(&url)).await.unwrap();
+        let alerted_until = Utc::now();
+
+        store
+            .deliver(&webhook.id, "retried", b"{}", alerted_until)
+            .await;
+
+        assert_eq!(received.lock().unwrap().len(), 3);
+        let webhook = store.get(&webhook.id).unwrap();
+        assert_eq!(webhook.alerted_until, Some(alerted_until));
+        assert!(store.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn refuses_local_targets_unless_allowed() {
+        let store = WebhookStore::open(None, false).unwrap();
+        let result = store
+            .register(new_webhook("http://127.0.0.1:8080/hook"))
+            .await;
+        assert!(matches!(result, Err(AppError::InvalidBody(_))));
+    }
+
+    #[tokio::test]
+    async fn caps_webhooks_per_url() {
+        let store = store();
+        for i in 0..MAX_WEBHOOKS_PER_URL {
+            let new = NewWebhook {
+                lon: i as f32,
+                ..new_webhook("http://127.0.0.1:8080/hook")
+            };
+            store.register(new).await.unwrap();
+        }
+        let result = store
+            .register(new_webhook("http://127.0.0.1:8080/hook"))
+            .await;
+        assert!(matches!(result, Err(AppError::TooManySubscriptions)));
+    }
+}
@@ -0,0 +1,180 @@
+use chrono::{DateTime, Days, Duration, NaiveDate, NaiveTime, Utc};
+use chrono_tz::Tz;
+
+use crate::{error::AppError, silam::Silam};
+
+/// Hours shown from the start of the local day: today, tomorrow and the day after.
+pub const FORECAST_HOURS: usize = 72;
+
+/// A run of hourly steps of the loaded data.
+pub struct ForecastWindow {
+    pub start: DateTime<Utc>,
+    pub hours: usize,
+}
+
+impl ForecastWindow {
+    /// Today, tomorrow and the day after in `tz`, as shown on the site.
+    pub fn three_days(tz: &Tz) -> ForecastWindow {
+        ForecastWindow {
+            start: start_of_day(Utc::now().with_timezone(tz).date_naive(), tz),
+            hours: FORECAST_HOURS,
+        }
+    }
+
+    /// `days` whole days in `tz` from the start of `date`, however many hours they have.
+    pub fn whole_days(date: NaiveDate, days: u64, tz: &Tz) -> Option<ForecastWindow> {
+        let start = start_of_day(date, tz);
+        let end = start_of_day(date.checked_add_days(Days::new(days))?, tz);
+        Some(ForecastWindow {
+            start,
+            hours: usize::try_from((end - start).num_hours()).ok()?,
+        })
+    }
+
+    /// Window from the `start` query param with at most one of `end`, `hours` or `days`.
+    ///
+    /// `start` is `today` (the default), `now`, `yesterday`, `tomorrow`, `first` for the start of
+    /// the data, or an RFC 3339 time. `end` is an RFC 3339 time or `last` for the end of the
+    /// data, and `days` counts whole days in `tz` from the day `start` is in. Without any of
+    /// them the window is `FORECAST_HOURS` long.
+    pub fn from_params(
+        start: Option<&str>,
+        end: Option<&str>,
+        hours: Option<usize>,
+        days: Option<u64>,
+        tz: &Tz,
+        silam: &Silam,
+    ) -> Result<ForecastWindow, AppError> {
+        let out_of_range = || AppError::WindowOutOfRange {
+            available_start: silam.start_time,
+            available_end: silam.end_time(),
+        };
+
+        let now = Utc::now();
+        let today = now.with_timezone(tz).date_naive();
+        let requested_start = match start.unwrap_or("today") {
+            "now" => now,
+            "today" => start_of_day(today, tz),
+            "yesterday" => start_of_day(today.pred_opt().ok_or_else(out_of_range)?, tz),
+            "tomorrow" => start_of_day(today.succ_opt().ok_or_else(out_of_range)?, tz),
+            "first" => silam.start_time,
+            time => parse_time(time).ok_or_else(|| {
+                AppError::InvalidWindow(format!(
+                    "start must be now, today, yesterday, tomorrow, first or an RFC 3339 time, not {:?}",
+                    time
+                ))
+            })?,
+        };
+        if requested_start < silam.start_time || requested_start >= silam.end_time() {
+            return Err(out_of_range());
+        }
+        // steps are hourly from the start of the data, so take the one the time falls in
+        let start =
+            silam.start_time + Duration::hours((requested_start - silam.start_time).num_hours());
+
+        let end = match (end, hours, days) {
+            (None, None, None) => add_hours(start, FORECAST_HOURS).ok_or_else(out_of_range)?,
+            (Some("last"), None, None) => silam.end_time(),
+            (Some(time), None, None) => parse_time(time).ok_or_else(|| {
+                AppError::InvalidWindow(format!(
+                    "end must be last or an RFC 3339 time, not {:?}",
+                    time
+                ))
+            })?,
+            (None, Some(hours), None) => add_hours(start, hours).ok_or_else(out_of_range)?,
+            (None, None, Some(days)) => {
+                let start_date = requested_start.with_timezone(tz).date_naive();
+                let end_date = start_date
+                    .checked_add_days(Days::new(days))
+                    .ok_or_else(out_of_range)?;
+                start_of_day(end_date, tz)
+            }
+            _ => {
+                return Err(AppError::InvalidWindow(
+                    "only one of end, hours or days can be given".to_string(),
+                ))
+            }
+        };
+        if end <= start {
+            return Err(AppError::InvalidWindow(
+                "window must end after it starts".to_string(),
+            ));
+        }
+        if end > silam.end_time() {
+            return Err(out_of_range());
+        }
+
+        // a partial last hour still needs its step
+        let minutes = (end - start).num_minutes();
+        let hours = usize::try_from((minutes + 59) / 60).map_err(|_| out_of_range())?;
+
+        Ok(ForecastWindow { start, hours })
+    }
+}
+
+/// When `date` starts in `tz`. On days the clocks skip midnight that is the first hour that
+/// exists.
+pub fn start_of_day(date: NaiveDate, tz: &Tz) -> DateTime<Utc> {
+    (0..24)
+        .filter_map(|hour| date.and_hms_opt(hour, 0, 0))
+        .find_map(|time| time.and_local_timezone(*tz).earliest())
+        .map(|start| start.to_utc())
+        .unwrap_or_else(|| date.and_time(NaiveTime::MIN).and_utc())
+}
+
+fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(time)
+        .ok()
+        .map(|time| time.to_utc())
+}
+
+fn add_hours(time: DateTime<Utc>, hours: usize) -> Option<DateTime<Utc>> {
+    let hours = i64::try_from(hours).ok().and_then(Duration::try_hours)?;
+    time.checked_add_signed(hours)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Europe::London;
+
+    fn utc(time: &str) -> DateTime<Utc> {
+        parse_time(time).unwrap()
+    }
+
+    fn date(date: &str) -> NaiveDate {
+        date.parse().unwrap()
+    }
+
+    #[test]
+    fn whole_days_across_spring_forward() {
+        // clocks go from 01:00 GMT to 02:00 BST on 2024-03-31
+        let window = ForecastWindow::whole_days(date("2024-03-31"), 1, &London).unwrap();
+        assert_eq!(window.start, utc("2024-03-31T00:00:00Z"));
+        assert_eq!(window.hours, 23);
+
+        let window = ForecastWindow::whole_days(date("2024-03-30"), 3, &London).unwrap();
+        assert_eq!(window.start, utc("2024-03-30T00:00:00Z"));
+        assert_eq!(window.hours, 24 + 23 + 24);
+        assert_eq!(
+            start_of_day(date("2024-04-01"), &London),
+            utc("2024-03-31T23:00:00Z")
+        );
+    }
+
+    #[test]
+    fn whole_days_across_fall_back() {
+        // clocks go from 02:00 BST back to 01:00 GMT on 2024-10-27
+        let window = ForecastWindow::whole_days(date("2024-10-27"), 1, &London).unwrap();
+        assert_eq!(window.start, utc("2024-10-26T23:00:00Z"));
+        assert_eq!(window.hours, 25);
+
+        let window = ForecastWindow::whole_days(date("2024-10-26"), 3, &London).unwrap();
+        assert_eq!(window.start, utc("2024-10-25T23:00:00Z"));
+        assert_eq!(window.hours, 24 + 25 + 24);
+        assert_eq!(
+            start_of_day(date("2024-10-28"), &London),
+            utc("2024-10-28T00:00:00Z")
+        );
+    }
+}